@@ -1,10 +1,10 @@
 #!/bin/bash
 
-# Start the first node
-cargo run --bin node -- --port 3030 &
+# Start the first node, producing blocks for development validator 0
+cargo run --bin node -- --port 3030 --peers 127.0.0.1:3031,127.0.0.1:3032 --validators 0 --dev &
 
-# Start the second node
-cargo run --bin node -- --port 3031 &
+# Start the second node, producing blocks for development validator 1
+cargo run --bin node -- --port 3031 --peers 127.0.0.1:3030,127.0.0.1:3032 --validators 1 --dev &
 
-# Start the third node
-cargo run --bin node -- --port 3032 &
+# Start the third node, producing blocks for development validator 2
+cargo run --bin node -- --port 3032 --peers 127.0.0.1:3030,127.0.0.1:3031 --validators 2 --dev &
@@ -0,0 +1,133 @@
+//! Response bodies shared by the REST routes and the JSON-RPC methods.
+
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::{Block, Blockchain, DropReason, DroppedTransaction, IncludedTransaction, Transaction, TransactionStatus};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceResponse {
+    pub address: String,
+    pub balance: u64,
+    pub nonce: u64, // Committed account nonce; `/nonce` also counts pending transactions
+}
+
+impl BalanceResponse {
+    pub fn new(address: String, blockchain: &Blockchain) -> Self {
+        let account = blockchain.get_account(&address);
+        BalanceResponse { address, balance: account.balance, nonce: account.nonce }
+    }
+}
+
+/// A transaction accepted into the mempool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubmitResponse {
+    pub tx_id: String,
+    pub status: String,
+}
+
+impl SubmitResponse {
+    pub fn pending(transaction: &Transaction) -> Self {
+        SubmitResponse { tx_id: transaction.id.clone(), status: "pending".to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionDetails {
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub status: TransactionStatus,
+    pub block_index: Option<u64>,      // Height of the including block
+    pub block_hash: Option<String>,
+    pub index_in_block: Option<usize>, // Position among the block's transactions
+    pub finalized: bool,
+    pub drop_reason: Option<DropReason>,
+}
+
+impl TransactionDetails {
+    pub fn pending(transaction: Transaction) -> Self {
+        TransactionDetails {
+            transaction,
+            status: TransactionStatus::Pending,
+            block_index: None,
+            block_hash: None,
+            index_in_block: None,
+            finalized: false,
+            drop_reason: None,
+        }
+    }
+
+    pub fn included(included: IncludedTransaction, finalized: bool) -> Self {
+        TransactionDetails {
+            transaction: included.transaction,
+            status: if finalized { TransactionStatus::Finalized } else { TransactionStatus::Included },
+            block_index: Some(included.block_index),
+            block_hash: Some(included.block_hash),
+            index_in_block: Some(included.index_in_block),
+            finalized,
+            drop_reason: None,
+        }
+    }
+
+    pub fn dropped(dropped: DroppedTransaction) -> Self {
+        TransactionDetails {
+            status: TransactionStatus::Dropped,
+            drop_reason: Some(dropped.reason),
+            ..Self::pending(dropped.transaction)
+        }
+    }
+
+    /// Finds a transaction in the chain, the mempool or among the dropped ones, in that order.
+    pub fn lookup(id: &str, blockchain: &Blockchain) -> Option<Self> {
+        if let Some(included) = blockchain.get_included_transaction(id) {
+            let finalized = blockchain.is_finalized(included.block_index);
+            return Some(Self::included(included, finalized));
+        }
+        blockchain
+            .get_transaction(id)
+            .map(Self::pending)
+            .or_else(|| blockchain.get_dropped_transaction(id).map(Self::dropped))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockDetails {
+    #[serde(flatten)]
+    pub block: Block,
+    pub finalized: bool,
+}
+
+impl BlockDetails {
+    pub fn new(block: Block, blockchain: &Blockchain) -> Self {
+        BlockDetails { finalized: blockchain.is_finalized(block.index), block }
+    }
+}
+
+/// Issuance and burns up to the tip, for `/supply`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SupplyResponse {
+    pub height: u64,
+    pub circulating: u64,       // Total minted less total burned
+    pub total_minted: u64,      // Genesis allocation, block rewards and mint transactions
+    pub total_burned: u64,      // Slashed stake
+    pub genesis: u64,
+    pub block_rewards: u64,
+    pub minted: u64,            // By mint transactions
+    pub next_block_reward: u64, // For a block with transactions; empty blocks earn the policy's bonus on top
+}
+
+impl SupplyResponse {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        let height = blockchain.next_block_index() - 1;
+        let supply = blockchain.get_supply();
+        SupplyResponse {
+            height,
+            circulating: supply.circulating(),
+            total_minted: supply.total_minted(),
+            total_burned: supply.burned,
+            genesis: supply.genesis,
+            block_rewards: supply.block_rewards,
+            minted: supply.minted,
+            next_block_reward: blockchain.monetary_policy().block_reward(height + 1, 1),
+        }
+    }
+}
@@ -0,0 +1,1891 @@
+use std::collections::{HashMap, HashSet};
+use sha2::{Sha256, Digest};
+use hex::encode;
+use serde::{Serialize, Deserialize};
+use sled::{Db, Tree};
+use sled::transaction::{ConflictableTransactionError, Transactional, TransactionalTree};
+use chrono::Utc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use log::{info, warn, debug};
+
+use crate::consensus::{ConsensusEngine, ConsensusKind};
+use crate::crypto;
+use crate::error::{BlockError, ChainError};
+use crate::finality::FinalityCertificate;
+use crate::genesis::Genesis;
+use crate::mempool::{Mempool, MempoolConfig, MempoolStatus};
+use crate::merkle;
+use crate::monetary::{MonetaryPolicy, Supply};
+use crate::network::Network;
+use crate::pos::{self, SlashEvent, SlashReason, Validator, ValidatorStatus};
+use crate::state::{Account, StateOverlay, StateTree, StateTrees, StateWrite};
+use crate::subscriptions::ChainEvent;
+
+/// What a transaction does. Every kind pays the transaction's `fee` on top.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    /// Moves `amount` from `sender` to `recipient`.
+    #[default]
+    Transfer,
+    /// Locks `amount` of the sender's balance as its own validator stake. `recipient` must be the sender.
+    Stake,
+    /// Unbonds `amount` from validator `recipient`: the sender's own stake if
+    /// `recipient` is the sender, otherwise the sender's delegation. The tokens
+    /// return to the balance after `pos::UNBONDING_PERIOD` blocks.
+    Unstake,
+    /// Locks `amount` of the sender's balance as stake delegated to validator `recipient`.
+    Delegate,
+    /// Reports validator `recipient` for double-signing, with the two blocks in `evidence`.
+    Evidence,
+    /// Issues `amount` new tokens to `recipient`. Only the policy's mint authority may send it.
+    Mint,
+}
+
+impl TransactionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionKind::Transfer => "transfer",
+            TransactionKind::Stake => "stake",
+            TransactionKind::Unstake => "unstake",
+            TransactionKind::Delegate => "delegate",
+            TransactionKind::Evidence => "evidence",
+            TransactionKind::Mint => "mint",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    #[serde(default)]
+    pub id: String, // Derived from the signed contents, see `compute_id`; clients may leave it out
+    #[serde(default)]
+    pub kind: TransactionKind,
+    pub sender: String,
+    pub recipient: String,
+    pub amount: u64,
+    #[serde(default)]
+    pub fee: u64,   // Paid to the proposer of the including block
+    pub nonce: u64, // Must equal the sender's next expected nonce
+    pub public_key: String,
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<DoubleSignEvidence>, // Only for `TransactionKind::Evidence`
+}
+
+impl Transaction {
+    /// The bytes covered by the sender's signature.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = format!("{}:{}:{}:{}:{}:{}", self.kind.as_str(), self.sender, self.recipient, self.amount, self.fee, self.nonce);
+        if let Some(evidence) = &self.evidence {
+            payload.push_str(&format!(":{}:{}", evidence.first.hash, evidence.second.hash));
+        }
+        payload.into_bytes()
+    }
+
+    /// Hash of the signing payload and the signature. Ids cannot be chosen,
+    /// so a transaction keeps its id wherever it is resubmitted and no one
+    /// can claim another sender's id first.
+    pub fn compute_id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.signing_payload());
+        hasher.update(hex::decode(&self.signature).unwrap_or_default());
+        encode(hasher.finalize())
+    }
+
+    /// Sets `id` if the client left it out.
+    pub fn fill_id(&mut self) {
+        if self.id.is_empty() {
+            self.id = self.compute_id();
+        }
+    }
+
+    /// Signs the transaction with the sender's `private_key` and derives its id.
+    pub fn sign(&mut self, private_key: &str) -> Result<(), ChainError> {
+        self.signature = crypto::sign(private_key, &self.signing_payload())?;
+        self.id = self.compute_id();
+        Ok(())
+    }
+
+    /// Hash of the whole signed transaction, id and signature included. The
+    /// block's Merkle root is built from these, so the block hash commits to
+    /// every transaction's contents and not only to its client-chosen id.
+    pub fn hash(&self) -> String {
+        encode(Sha256::digest(serde_json::to_vec(self).unwrap()))
+    }
+
+    /// What the sender's balance pays: the fee, plus the amount for the kinds
+    /// that spend it. `InvalidAmount` if the sum overflows.
+    pub fn debit(&self) -> Result<u64, ChainError> {
+        let spent = match self.kind {
+            TransactionKind::Unstake => 0, // Unbonded tokens come out of stake, not the balance
+            TransactionKind::Evidence => 0,
+            TransactionKind::Mint => 0, // Minted tokens are new
+            _ => self.amount,
+        };
+        spent.checked_add(self.fee).ok_or(ChainError::InvalidAmount("Amount plus fee overflows"))
+    }
+
+    /// Serialized size, the denominator of the fee per byte.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).unwrap().len()
+    }
+
+    /// Whether this transaction pays more per byte than `other`.
+    pub fn pays_more_per_byte_than(&self, other: &Transaction) -> bool {
+        self.fee as u128 * other.size() as u128 > other.fee as u128 * self.size() as u128
+    }
+
+    /// Checks that `public_key` belongs to `sender`, that it signed this
+    /// transaction and that `id` is derived from it.
+    pub fn verify_signature(&self) -> Result<(), ChainError> {
+        if crypto::address_from_public_key(&self.public_key)? != self.sender {
+            return Err(ChainError::PublicKeyMismatch);
+        }
+        crypto::verify(&self.public_key, &self.signing_payload(), &self.signature)?;
+        if self.id != self.compute_id() {
+            return Err(ChainError::IdMismatch { expected: self.compute_id() });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub index: u64,
+    pub previous_hash: String,
+    pub timestamp: u64,
+    pub merkle_root: String, // Merkle root over the hashes of `transactions`
+    pub transactions: Vec<Transaction>,
+    pub proof: u64,
+    #[serde(default)]
+    pub difficulty: u32, // Leading zero bits the hash needs under proof-of-work, 0 under proof-of-stake
+    #[serde(default)]
+    pub reward: u64, // Newly issued tokens paid to the validator, on top of the fees
+    pub hash: String,
+    pub validator: String,  // Proposer the block was produced for
+    pub public_key: String, // The proposer's public key
+    pub signature: String,  // The proposer's signature over `hash`
+}
+
+impl Block {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            previous_hash: self.previous_hash.clone(),
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root.clone(),
+            proof: self.proof,
+            difficulty: self.difficulty,
+            reward: self.reward,
+            hash: self.hash.clone(),
+            validator: self.validator.clone(),
+            public_key: self.public_key.clone(),
+            signature: self.signature.clone(),
+        }
+    }
+}
+
+/// A block without its transactions. Enough to check who signed it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub previous_hash: String,
+    pub timestamp: u64,
+    pub merkle_root: String,
+    pub proof: u64,
+    #[serde(default)]
+    pub difficulty: u32,
+    #[serde(default)]
+    pub reward: u64,
+    pub hash: String,
+    pub validator: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl BlockHeader {
+    /// Hashes every header field except the hash and signature, separated by
+    /// `:` so that no two headers hash the same input. Transactions are
+    /// committed to through `merkle_root`.
+    pub fn calculate_hash(&self) -> String {
+        let input = format!(
+            "{}:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.index, self.previous_hash, self.timestamp, self.merkle_root, self.proof, self.difficulty, self.reward, self.validator, "MOHSIN"
+        );
+        encode(Sha256::digest(input))
+    }
+
+    /// Checks that `hash` matches the header, that `public_key` belongs to
+    /// `validator` and that it signed the hash.
+    pub fn verify_signature(&self) -> Result<(), ChainError> {
+        if self.calculate_hash() != self.hash {
+            return Err(ChainError::InvalidBlock(BlockError::HashMismatch));
+        }
+        if crypto::address_from_public_key(&self.public_key)? != self.validator {
+            return Err(ChainError::PublicKeyMismatch);
+        }
+        crypto::verify(&self.public_key, self.hash.as_bytes(), &self.signature)
+    }
+}
+
+/// Two different blocks signed by the same validator for the same height and slot.
+/// An honest validator proposes at most once per slot, even across forks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DoubleSignEvidence {
+    pub first: BlockHeader,
+    pub second: BlockHeader,
+}
+
+impl DoubleSignEvidence {
+    pub fn verify(&self) -> Result<(), ChainError> {
+        let (first, second) = (&self.first, &self.second);
+        if first.validator != second.validator || first.index != second.index || pos::slot_at(first.timestamp) != pos::slot_at(second.timestamp) {
+            return Err(ChainError::InvalidEvidence("blocks are not from the same validator, height and slot"));
+        }
+        if first.hash == second.hash {
+            return Err(ChainError::InvalidEvidence("blocks are identical"));
+        }
+        first.verify_signature()?;
+        second.verify_signature()
+    }
+}
+
+/// An entry of the transaction index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IncludedTransaction {
+    pub block_index: u64,
+    #[serde(default)]
+    pub block_hash: String,
+    #[serde(default)]
+    pub index_in_block: usize,
+    pub transaction: Transaction,
+}
+
+/// Where a transaction is in its lifecycle. Pending transactions wait in the
+/// mempool; included ones are in a block that may still be rolled back;
+/// finalized ones are in a finalized block; dropped ones left the mempool
+/// without being included.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Pending,
+    Included,
+    Finalized,
+    Dropped,
+}
+
+/// Why a transaction was dropped, from the `ChainError` that dropped it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DropReason {
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&ChainError> for DropReason {
+    fn from(e: &ChainError) -> Self {
+        DropReason { code: e.code().to_string(), message: e.to_string() }
+    }
+}
+
+/// An entry of the dropped transaction index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DroppedTransaction {
+    pub height: u64, // Height of the tip when the transaction was dropped
+    pub reason: DropReason,
+    pub transaction: Transaction,
+}
+
+/// Limits for block production and the monetary policy, from the genesis,
+/// and for the mempool, from the command line.
+#[derive(Debug, Clone)]
+struct ChainConfig {
+    max_block_transactions: usize,
+    max_block_bytes: usize, // Serialized size of the included transactions
+    max_missed_slots: u64,  // Consecutive missed slots before a validator is slashed for downtime
+    mempool: MempoolConfig,
+    consensus: ConsensusKind,
+    monetary: MonetaryPolicy,
+}
+
+/// Length of a slot. Each slot has one stake-weighted proposer.
+pub const BLOCK_TIME: Duration = Duration::from_secs(2);
+/// Events kept for subscribers that fall behind.
+const EVENT_BUFFER: usize = 1024;
+
+/// Everything a block changed, so it can be rolled back during a reorganization.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BlockUndo {
+    state: Vec<StateWrite>,    // Stored value of every state key the block wrote
+    transactions: Vec<String>, // Ids the block added to the transaction index
+}
+
+/// Outcome of running a block through the state-transition function.
+struct BlockExecution<'a> {
+    state: StateOverlay<'a>,
+    applied: Vec<Transaction>,
+    dropped: Vec<(Transaction, ChainError)>,
+}
+
+/// Trees written together with the state when a block is added or rolled back.
+#[derive(Clone, Copy)]
+enum ChainTree {
+    Blocks,
+    Transactions,
+    Undo,
+    BlockHashes,
+    AddressTransactions,
+}
+
+type ChainWrite = (ChainTree, Vec<u8>, Option<Vec<u8>>);
+
+/// Length of an `address_transactions` key, see `address_key`.
+const ADDRESS_KEY_LEN: usize = 20 + 8 + 4;
+
+/// Key of the transaction at `position` in block `index` in the address
+/// index of `address`, which must pass `crypto::is_address`. Fixed-width and
+/// big-endian, so an address's keys sort in chain order and cannot be forged
+/// by another address.
+fn address_key(address: &str, index: u64, position: u32) -> Vec<u8> {
+    let mut key = hex::decode(address).expect("Not an address");
+    key.extend(index.to_be_bytes());
+    key.extend(position.to_be_bytes());
+    key
+}
+
+#[derive(Clone)]
+pub struct Blockchain {
+    db: Db,
+    blocks: Tree,         // Block index (big-endian u64) -> Block
+    transactions: Tree,   // Transaction ID -> IncludedTransaction
+    dropped: Tree,        // Transaction ID -> DroppedTransaction, for transactions that left the mempool unincluded
+    finality: Tree,       // Block index (big-endian u64) -> FinalityCertificate, for finalized blocks
+    undo: Tree,           // Block index (big-endian u64) -> BlockUndo
+    block_hashes: Tree,   // Block hash -> block index (big-endian u64)
+    address_transactions: Tree, // Address bytes ++ block index (big-endian u64) ++ position (big-endian u32) -> transaction ID, for senders and recipients
+    state: StateTrees,    // Accounts, validators, delegations, unbonding stake and the token supply
+    block_index: Arc<Mutex<u64>>,
+    chain_work: Arc<Mutex<u128>>, // Sum of the consensus engine's block work from block 1 to the tip
+    mempool: Arc<Mutex<Mempool>>,
+    state_lock: Arc<Mutex<()>>, // Serializes writes to the state trees
+    consensus: Arc<dyn ConsensusEngine>,
+    config: ChainConfig,
+    events: broadcast::Sender<ChainEvent>, // New blocks and mempool transactions, for subscribers
+    chain_id: String,
+    genesis_hash: String, // Hash of block 0
+}
+
+impl Blockchain {
+    /// Opens the chain stored at `path`, creating it from `genesis` if the
+    /// database is empty. Panics if the database holds a chain with another genesis.
+    pub fn new(path: &str, genesis: &Genesis, mempool: MempoolConfig) -> Self {
+        let db = sled::open(path).expect("Failed to open database");
+        Self::open(db, path, genesis, mempool)
+    }
+
+    /// A chain from `genesis` in a database that is deleted when it is dropped.
+    #[cfg(test)]
+    pub(crate) fn temporary(genesis: &Genesis) -> Self {
+        let db = sled::Config::new().temporary(true).open().expect("Failed to open database");
+        Self::open(db, "(temporary)", genesis, MempoolConfig::default())
+    }
+
+    fn open(db: Db, path: &str, genesis: &Genesis, mempool: MempoolConfig) -> Self {
+        let config = ChainConfig {
+            max_block_transactions: genesis.consensus.max_block_transactions,
+            max_block_bytes: genesis.consensus.max_block_bytes,
+            max_missed_slots: genesis.consensus.max_missed_slots,
+            mempool,
+            consensus: genesis.consensus.engine,
+            monetary: genesis.monetary_policy.clone(),
+        };
+        let genesis_block = genesis.block();
+        let blocks = db.open_tree("blocks").expect("Failed to open blocks tree");
+        let transactions = db.open_tree("transactions").expect("Failed to open transactions tree");
+        let dropped = db.open_tree("dropped").expect("Failed to open dropped transactions tree");
+        let finality = db.open_tree("finality").expect("Failed to open finality tree");
+        let undo = db.open_tree("undo").expect("Failed to open undo tree");
+        let block_hashes = db.open_tree("block_hashes").expect("Failed to open block hashes tree");
+        let address_transactions = db.open_tree("address_transactions").expect("Failed to open address transactions tree");
+        let state = StateTrees::open(&db);
+
+        // Continue from the last stored block, if any
+        let last_block: Option<Block> = blocks
+            .last()
+            .expect("Failed to read last block from database")
+            .map(|(_, value)| serde_json::from_slice(&value).expect("Corrupt block in database"));
+        let next_index = last_block.as_ref().map_or(1, |block| block.index + 1);
+        let is_fresh = last_block.is_none() && state.is_empty();
+        let consensus = config.consensus.engine();
+        let chain_work = blocks
+            .range(1u64.to_be_bytes()..)
+            .values()
+            .map(|value| serde_json::from_slice::<Block>(&value.expect("Failed to read block from database")).expect("Corrupt block in database"))
+            .fold(0u128, |work, block| work.saturating_add(consensus.block_work(&block)));
+
+        let blockchain = Blockchain {
+            db,
+            blocks,
+            transactions,
+            dropped,
+            finality,
+            undo,
+            block_hashes,
+            address_transactions,
+            state,
+            block_index: Arc::new(Mutex::new(next_index)),
+            chain_work: Arc::new(Mutex::new(chain_work)),
+            mempool: Arc::new(Mutex::new(Mempool::new(config.mempool.clone()))),
+            state_lock: Arc::new(Mutex::new(())),
+            consensus,
+            config,
+            events: broadcast::channel(EVENT_BUFFER).0,
+            chain_id: genesis.chain_id.clone(),
+            genesis_hash: genesis_block.hash.clone(),
+        };
+
+        if is_fresh {
+            blockchain.create_genesis(genesis, genesis_block);
+        } else {
+            match blockchain.get_block(0) {
+                Some(block) if block.hash == genesis_block.hash => {}
+                stored => panic!(
+                    "Database {} holds a chain with genesis {}, but the genesis file hashes to {}",
+                    path,
+                    stored.map_or_else(|| "(none)".to_string(), |block| block.hash),
+                    genesis_block.hash
+                ),
+            }
+            info!("Loaded chain {} from {}, next block index: {}", genesis.chain_id, path, next_index);
+            let old_address_keys = blockchain.address_transactions
+                .first()
+                .expect("Failed to read address transaction index")
+                .is_some_and(|(key, _)| key.len() != ADDRESS_KEY_LEN);
+            if old_address_keys {
+                blockchain.address_transactions.clear().expect("Failed to clear address transaction index");
+            }
+            if (blockchain.block_hashes.is_empty() || old_address_keys) && !blockchain.blocks.is_empty() {
+                blockchain.rebuild_indexes(); // Database from before the explorer indexes existed, or with text address keys
+            }
+        }
+
+        blockchain
+    }
+
+    /// Writes block 0 and the initial balances, validators and supply of `genesis`.
+    fn create_genesis(&self, genesis: &Genesis, block: Block) {
+        let mut state = StateOverlay::new(&self.state);
+        for (address, balance) in &genesis.balances {
+            let mut account = state.account(address);
+            account.balance += balance;
+            state.set_account(address, &account);
+        }
+        for entry in &genesis.validators {
+            let mut validator = state.validator(&entry.address).unwrap_or_else(|| Validator::new(&entry.address));
+            validator.stake += entry.stake;
+            validator.update_status();
+            state.set_validator(&entry.address, Some(&validator));
+        }
+        let allocated = genesis.balances.values().chain(genesis.validators.iter().map(|entry| &entry.stake)).sum();
+        state.set_supply(&Supply { genesis: allocated, ..Supply::default() });
+
+        let mut chain_writes = vec![(ChainTree::Blocks, 0u64.to_be_bytes().to_vec(), Some(serde_json::to_vec(&block).unwrap()))];
+        chain_writes.extend(Self::index_entries(&block).into_iter().map(|(tree, key, value)| (tree, key, Some(value))));
+        self.commit(&state.writes(), &chain_writes);
+        self.db.flush().expect("Failed to flush database");
+        info!("Created chain {} from genesis {}", genesis.chain_id, block.hash);
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    /// Hash of block 0, which commits to the genesis file.
+    pub fn genesis_hash(&self) -> &str {
+        &self.genesis_hash
+    }
+
+    /// Opens a tree for data this node keeps next to the chain, such as the
+    /// faucet's. It is not part of the chain state and is never rolled back.
+    pub fn open_local_tree(&self, name: &str) -> Tree {
+        self.db.open_tree(name).unwrap_or_else(|e| panic!("Failed to open {} tree: {}", name, e))
+    }
+
+    pub fn get_transaction(&self, id: &str) -> Option<Transaction> {
+        let included = self.get_included_transaction(id).map(|included| included.transaction);
+        included.or_else(|| {
+            let mempool = self.mempool.lock().unwrap();
+            mempool.get(id).cloned()
+        })
+    }
+
+    /// Looks up a transaction that is part of the chain, with the block that includes it.
+    pub fn get_included_transaction(&self, id: &str) -> Option<IncludedTransaction> {
+        self.transactions
+            .get(id)
+            .expect("Failed to get transaction from database")
+            .map(|value| serde_json::from_slice(&value).expect("Corrupt transaction in database"))
+    }
+
+    /// Looks up a transaction that left the mempool without being included.
+    pub fn get_dropped_transaction(&self, id: &str) -> Option<DroppedTransaction> {
+        self.dropped
+            .get(id)
+            .expect("Failed to get dropped transaction from database")
+            .map(|value| serde_json::from_slice(&value).expect("Corrupt dropped transaction in database"))
+    }
+
+    /// Removes a transaction from the mempool, if it is still there, and records why. `height` is the current tip.
+    fn drop_transaction(&self, mempool: &mut Mempool, tx: &Transaction, reason: &ChainError, height: u64) {
+        debug!("Dropping transaction {}: {}", tx.id, reason);
+        mempool.remove(&tx.id);
+        let dropped = DroppedTransaction { height, reason: reason.into(), transaction: tx.clone() };
+        self.dropped
+            .insert(tx.id.as_bytes(), serde_json::to_vec(&dropped).unwrap())
+            .expect("Failed to write dropped transaction to database");
+        mempool.note_dropped(dropped);
+    }
+
+    /// Height of the last finalized block, 0 if none is.
+    pub fn finalized_height(&self) -> u64 {
+        self.finality
+            .last()
+            .expect("Failed to read finality from database")
+            .map_or(0, |(key, _)| u64::from_be_bytes(key.as_ref().try_into().unwrap()))
+    }
+
+    /// Finalizing a block finalizes all of its ancestors.
+    pub fn is_finalized(&self, index: u64) -> bool {
+        index <= self.finalized_height()
+    }
+
+    pub fn get_finality_certificate(&self, index: u64) -> Option<FinalityCertificate> {
+        self.finality
+            .get(index.to_be_bytes())
+            .expect("Failed to read finality from database")
+            .map(|value| serde_json::from_slice(&value).expect("Corrupt finality certificate in database"))
+    }
+
+    /// Marks the block in `certificate` and its ancestors as final. The caller
+    /// has checked the precommits.
+    pub fn finalize(&self, certificate: FinalityCertificate) -> Result<(), ChainError> {
+        let _state = self.state_lock.lock().unwrap();
+        if certificate.height <= self.finalized_height() {
+            return Err(ChainError::AlreadyFinalized);
+        }
+        if self.get_block(certificate.height).is_none_or(|block| block.hash != certificate.hash) {
+            return Err(ChainError::NotOnChain);
+        }
+        self.finality
+            .insert(certificate.height.to_be_bytes(), serde_json::to_vec(&certificate).unwrap())
+            .expect("Failed to write finality to database");
+        self.db.flush().expect("Failed to flush database");
+        Ok(())
+    }
+
+    pub fn get_last_block(&self) -> Option<Block> {
+        let last_index = {
+            let index = self.block_index.lock().unwrap();
+            *index - 1
+        };
+        self.get_block(last_index)
+    }
+
+    /// Total work of the chain up to the tip, as weighed by the consensus engine.
+    pub fn chain_work(&self) -> u128 {
+        *self.chain_work.lock().unwrap()
+    }
+
+    /// Index the next block must have to extend the chain.
+    pub fn next_block_index(&self) -> u64 {
+        *self.block_index.lock().unwrap()
+    }
+
+    /// Hash a block at `index` must reference as its `previous_hash`.
+    fn parent_hash(&self, index: u64) -> Option<String> {
+        self.get_block(index.checked_sub(1)?).map(|block| block.hash)
+    }
+
+    pub fn get_blocks(&self, from: u64, limit: u64) -> Vec<Block> {
+        self.blocks
+            .range(from.to_be_bytes()..)
+            .take(limit as usize)
+            .map(|entry| {
+                let (_, value) = entry.expect("Failed to read block from database");
+                serde_json::from_slice(&value).expect("Corrupt block in database")
+            })
+            .collect()
+    }
+
+    /// Receives every block added to the tip and every transaction added to the mempool from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<Block> {
+        self.block_hashes
+            .get(hash)
+            .expect("Failed to read block hash index")
+            .and_then(|index| self.get_block(u64::from_be_bytes(index.as_ref().try_into().unwrap())))
+    }
+
+    /// Transactions that `address` sent or received, in chain order, starting
+    /// at block `from`.
+    pub fn get_address_transactions(&self, address: &str, from: u64, limit: u64) -> Vec<IncludedTransaction> {
+        if !crypto::is_address(address) {
+            return Vec::new();
+        }
+        let start = address_key(address, from, 0);
+        self.address_transactions
+            .range(start.as_slice()..)
+            .map(|entry| entry.expect("Failed to read address transaction index"))
+            .take_while(|(key, _)| key.starts_with(&start[..20]))
+            .take(limit as usize)
+            .filter_map(|(_, id)| self.get_included_transaction(std::str::from_utf8(&id).unwrap()))
+            .collect()
+    }
+
+    /// Secondary index entries for `block`: its hash, and every address its
+    /// transactions touch. Stored with the block and removed with it.
+    fn index_entries(block: &Block) -> Vec<(ChainTree, Vec<u8>, Vec<u8>)> {
+        let mut entries = vec![(ChainTree::BlockHashes, block.hash.as_bytes().to_vec(), block.index.to_be_bytes().to_vec())];
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let mut addresses = vec![&tx.sender];
+            if tx.recipient != tx.sender {
+                addresses.push(&tx.recipient);
+            }
+            // Blocks from before recipients were checked may name something else
+            for address in addresses.into_iter().filter(|address| crypto::is_address(address)) {
+                let key = address_key(address, block.index, position as u32);
+                entries.push((ChainTree::AddressTransactions, key, tx.id.as_bytes().to_vec()));
+            }
+        }
+        entries
+    }
+
+    fn rebuild_indexes(&self) {
+        let mut count = 0;
+        for block in self.blocks.iter().values() {
+            let block: Block = serde_json::from_slice(&block.expect("Failed to read block from database")).expect("Corrupt block in database");
+            let writes: Vec<ChainWrite> = Self::index_entries(&block)
+                .into_iter()
+                .map(|(tree, key, value)| (tree, key, Some(value)))
+                .collect();
+            self.commit(&[], &writes);
+            count += 1;
+        }
+        self.db.flush().expect("Failed to flush database");
+        info!("Rebuilt block hash and address indexes for {} blocks", count);
+    }
+
+    pub fn add_block(&self, block: Block) -> Result<(), BlockError> {
+        let _state = self.state_lock.lock().unwrap();
+        self.apply_block(block)
+    }
+
+    /// Appends a block to the tip and applies it to the account state.
+    /// The caller must hold `state_lock`.
+    fn apply_block(&self, block: Block) -> Result<(), BlockError> {
+        let mut block_index = self.block_index.lock().unwrap();
+        let mut execution = self.validate_block(&block, *block_index)?;
+
+        let mut supply = execution.state.supply();
+        if !supply.can_issue(block.reward) {
+            return Err(BlockError::SupplyOverflow);
+        }
+        supply.block_rewards += block.reward;
+        execution.state.set_supply(&supply);
+        let mut validator_account = execution.state.account(&block.validator);
+        validator_account.balance = validator_account.balance.checked_add(block.reward).ok_or(BlockError::SupplyOverflow)?;
+        execution.state.set_account(&block.validator, &validator_account);
+
+        let block_undo = BlockUndo {
+            state: execution.state.previous_values(),
+            transactions: execution.applied.iter().map(|tx| tx.id.clone()).collect(),
+        };
+
+        // Store the block, the new state, the transaction index and the undo record atomically
+        let key = block.index.to_be_bytes().to_vec();
+        let mut chain_writes = vec![
+            (ChainTree::Blocks, key.clone(), Some(serde_json::to_vec(&block).unwrap())),
+            (ChainTree::Undo, key, Some(serde_json::to_vec(&block_undo).unwrap())),
+        ];
+        for (index_in_block, tx) in execution.applied.iter().enumerate() {
+            let included = IncludedTransaction { block_index: block.index, block_hash: block.hash.clone(), index_in_block, transaction: tx.clone() };
+            chain_writes.push((ChainTree::Transactions, tx.id.as_bytes().to_vec(), Some(serde_json::to_vec(&included).unwrap())));
+        }
+        chain_writes.extend(Self::index_entries(&block).into_iter().map(|(tree, key, value)| (tree, key, Some(value))));
+        self.commit(&execution.state.writes(), &chain_writes);
+        *block_index = block.index + 1;
+        let mut chain_work = self.chain_work.lock().unwrap();
+        *chain_work = chain_work.saturating_add(self.consensus.block_work(&block));
+        drop(chain_work);
+
+        // Included transactions leave the mempool, whichever node mined the block,
+        // along with the ones whose nonce it used up and the ones that waited too long
+        {
+            let mut mempool = self.mempool.lock().unwrap();
+            let mut dropped = mempool.remove_included(&block.transactions, |sender| self.get_account(sender).nonce);
+            dropped.extend(mempool.expire(Utc::now().timestamp() as u64));
+            for (tx, reason) in &dropped {
+                self.drop_transaction(&mut mempool, tx, reason, block.index);
+            }
+        }
+
+        info!("Block added with index: {}, hash: {}, validator: {}, transactions: {}", block.index, block.hash, block.validator, block.transactions.len());
+
+        // Make sure the block and its state changes survive a restart
+        self.db.flush().expect("Failed to flush database");
+        let _ = self.events.send(ChainEvent::NewHead(block)); // Fails only without subscribers
+        Ok(())
+    }
+
+    /// Checks everything about `block` that does not depend on who sent it:
+    /// that it extends the tip (`next_index`), its header (see
+    /// `verify_header`), the consensus engine's proposer rules against the
+    /// state, its size, its merkle root, that every transaction applies, and
+    /// its reward. Returns the state changes of its transactions.
+    fn validate_block(&self, block: &Block, next_index: u64) -> Result<BlockExecution<'_>, BlockError> {
+        if block.index != next_index {
+            return Err(BlockError::UnexpectedIndex { expected: next_index, found: block.index });
+        }
+        let parent = block.index.checked_sub(1).and_then(|index| self.get_block(index)).ok_or(BlockError::UnknownParent)?;
+        self.verify_header(block, &parent, &[])?;
+        self.consensus.verify(self, block)?;
+
+        if block.transactions.len() > self.config.max_block_transactions {
+            return Err(BlockError::TooManyTransactions);
+        }
+        let block_bytes: usize = block.transactions.iter().map(Transaction::size).sum();
+        if block_bytes > self.config.max_block_bytes {
+            return Err(BlockError::TooLarge);
+        }
+        let hashes: Vec<String> = block.transactions.iter().map(Transaction::hash).collect();
+        if merkle::merkle_root(&hashes) != block.merkle_root {
+            return Err(BlockError::MerkleRootMismatch);
+        }
+
+        let execution = self.execute_block(block.index, block.timestamp, block.transactions.clone(), &block.validator);
+        if let Some((tx, reason)) = execution.dropped.first() {
+            return Err(BlockError::InvalidTransaction { id: tx.id.clone(), reason: Box::new(reason.clone()) });
+        }
+
+        let expected = self.config.monetary.block_reward(block.index, block.transactions.len());
+        if block.reward != expected {
+            return Err(BlockError::WrongReward { expected, found: block.reward });
+        }
+        Ok(execution)
+    }
+
+    /// Checks what can be checked of `block` from its header and `parent`
+    /// alone: that it links to `parent`, that its hash matches the header and
+    /// its producer signed it, its timestamp, and the consensus engine's seal.
+    fn verify_header(&self, block: &Block, parent: &Block, earlier: &[Block]) -> Result<(), BlockError> {
+        if block.index != parent.index + 1 {
+            return Err(BlockError::UnexpectedIndex { expected: parent.index + 1, found: block.index });
+        }
+        if block.previous_hash != parent.hash {
+            return Err(BlockError::UnknownParent);
+        }
+
+        let header = block.header();
+        if header.calculate_hash() != block.hash {
+            return Err(BlockError::HashMismatch);
+        }
+        header.verify_signature().map_err(|e| BlockError::InvalidSignature(Box::new(e)))?;
+
+        if block.timestamp < parent.timestamp {
+            return Err(BlockError::TimestampBeforeParent);
+        }
+        // A block for a slot that has not started would make the nodes whose
+        // clocks are right skip their own slots, and count them as missed
+        if pos::slot_at(block.timestamp) > pos::slot_at(Utc::now().timestamp() as u64) {
+            return Err(BlockError::TimestampInFuture);
+        }
+        self.consensus.verify_header(self, block, parent, earlier)
+    }
+
+    /// Runs `verify_header` on every block of `blocks`, the first on top of
+    /// `parent`. `earlier` holds the already checked blocks of the same branch
+    /// up to `parent`, if it is not on this chain. Cheap next to applying the
+    /// blocks, so a branch is checked this way before anything is rolled back for it.
+    pub fn verify_branch(&self, parent: &Block, earlier: &[Block], blocks: &[Block]) -> Result<(), BlockError> {
+        let mut branch = earlier.to_vec();
+        let mut parent = parent;
+        for block in blocks {
+            self.verify_header(block, parent, &branch)?;
+            branch.push(block.clone());
+            parent = block;
+        }
+        Ok(())
+    }
+
+    /// Removes the tip block and restores the account state from before it.
+    /// The caller must hold `state_lock`.
+    fn rollback_block(&self) -> Option<Block> {
+        let mut block_index = self.block_index.lock().unwrap();
+        let index = block_index.checked_sub(1).filter(|index| *index > 0)?;
+        let block = self.get_block(index)?;
+        let block_undo: BlockUndo = self.undo
+            .get(index.to_be_bytes())
+            .expect("Failed to get undo record from database")
+            .map(|value| serde_json::from_slice(&value).expect("Corrupt undo record in database"))
+            .expect("Missing undo record for block");
+
+        let key = index.to_be_bytes().to_vec();
+        let mut chain_writes = vec![(ChainTree::Blocks, key.clone(), None), (ChainTree::Undo, key, None)];
+        for id in &block_undo.transactions {
+            chain_writes.push((ChainTree::Transactions, id.as_bytes().to_vec(), None));
+        }
+        chain_writes.extend(Self::index_entries(&block).into_iter().map(|(tree, key, _)| (tree, key, None)));
+        self.commit(&block_undo.state, &chain_writes);
+        *block_index = index;
+        let mut chain_work = self.chain_work.lock().unwrap();
+        *chain_work = chain_work.saturating_sub(self.consensus.block_work(&block));
+        drop(chain_work);
+
+        info!("Block rolled back with index: {}, hash: {}", block.index, block.hash);
+        let _ = self.events.send(ChainEvent::RolledBack(block.clone()));
+        Some(block)
+    }
+
+    /// Writes state changes and chain writes in one sled transaction.
+    fn commit(&self, state_writes: &[StateWrite], chain_writes: &[ChainWrite]) {
+        fn write(tree: &TransactionalTree, key: &[u8], value: &Option<Vec<u8>>) -> Result<(), ConflictableTransactionError> {
+            match value {
+                Some(value) => tree.insert(key, value.as_slice())?,
+                None => tree.remove(key)?,
+            };
+            Ok(())
+        }
+
+        let mut trees: Vec<&Tree> = self.state.all().iter().collect();
+        let chain_offset = trees.len();
+        trees.extend([&self.blocks, &self.transactions, &self.undo, &self.block_hashes, &self.address_transactions]);
+
+        trees.as_slice()
+            .transaction(|trees| {
+                for ((tree, key), value) in state_writes {
+                    write(&trees[*tree as usize], key.as_bytes(), value)?;
+                }
+                for (tree, key, value) in chain_writes {
+                    write(&trees[chain_offset + *tree as usize], key, value)?;
+                }
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .expect("Failed to write to database");
+    }
+
+    /// Fork choice: `branch`, replacing our blocks from `branch[0].index` on,
+    /// wins if it has more work than the blocks it replaces, as weighed by the
+    /// consensus engine, and on equal work if its tip has the lower hash, so
+    /// that every node picks the same chain.
+    pub fn is_better_branch(&self, branch: &[Block]) -> bool {
+        let (first, new_tip) = match (branch.first(), branch.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return false,
+        };
+        let branch_work = branch.iter().fold(0u128, |work, block| work.saturating_add(self.consensus.block_work(block)));
+        let replaced: Vec<Block> = (first.index..self.next_block_index()).filter_map(|index| self.get_block(index)).collect();
+        let replaced_work = replaced.iter().fold(0u128, |work, block| work.saturating_add(self.consensus.block_work(block)));
+        match replaced.last() {
+            None => true,
+            Some(tip) => branch_work > replaced_work || (branch_work == replaced_work && new_tip.hash < tip.hash),
+        }
+    }
+
+    /// Switches to `branch`, a run of consecutive blocks that replaces every
+    /// block from `branch[0].index` on. The replaced blocks are rolled back and
+    /// their transactions that the branch does not include return to the mempool,
+    /// or are recorded as dropped if the mempool turns them away.
+    /// If any branch block fails to apply, the original chain is restored.
+    pub fn reorganize(&self, branch: Vec<Block>) -> Result<(), BlockError> {
+        let _state = self.state_lock.lock().unwrap();
+
+        let (first, new_tip) = match (branch.first(), branch.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(BlockError::EmptyBranch),
+        };
+        let fork_index = first.index;
+        if fork_index == 0 || fork_index > self.next_block_index() {
+            return Err(BlockError::BranchNotConnected);
+        }
+        if self.is_finalized(fork_index) {
+            return Err(BlockError::RevertsFinalizedBlock);
+        }
+
+        // Check that the whole branch links up and that every block is sealed
+        // and signed before touching any state, so that the work it claims is real
+        let parent = self.get_block(fork_index - 1).ok_or(BlockError::BranchNotConnected)?;
+        self.verify_branch(&parent, &[], &branch)?;
+
+        if !self.is_better_branch(&branch) {
+            return Err(BlockError::BranchNotBetter);
+        }
+
+        let mut abandoned = Vec::new();
+        while self.next_block_index() > fork_index {
+            abandoned.push(self.rollback_block().expect("Failed to roll back block"));
+        }
+
+        for block in &branch {
+            if let Err(e) = self.apply_block(block.clone()) {
+                warn!("Reorganization failed at block {}: {}. Restoring the previous chain", block.index, e);
+                while self.next_block_index() > fork_index {
+                    self.rollback_block();
+                }
+                for block in abandoned.into_iter().rev() {
+                    self.apply_block(block).expect("Failed to restore the previous chain");
+                }
+                return Err(e);
+            }
+        }
+
+        // Transactions that only the abandoned blocks included get another
+        // chance, and the ones the mempool turns away are recorded as dropped
+        let now = Utc::now().timestamp() as u64;
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut pending = Vec::new();
+        for tx in abandoned.into_iter().rev().flat_map(|block| block.transactions) {
+            let included = self.transactions
+                .contains_key(tx.id.as_bytes())
+                .expect("Failed to read transaction index");
+            if included || mempool.contains(&tx.id) {
+                continue;
+            }
+            let account = self.get_account(&tx.sender);
+            match mempool.insert(tx.clone(), &account, now) {
+                Ok(evicted) => {
+                    for (tx, reason) in &evicted {
+                        self.drop_transaction(&mut mempool, tx, reason, new_tip.index);
+                    }
+                    pending.push(tx);
+                }
+                Err(e) => self.drop_transaction(&mut mempool, &tx, &e, new_tip.index),
+            }
+        }
+        drop(mempool);
+        for tx in pending {
+            let _ = self.events.send(ChainEvent::PendingTransaction(Box::new(tx)));
+        }
+
+        info!("Reorganized chain from index {}, new tip: {} ({})", fork_index, new_tip.index, new_tip.hash);
+        Ok(())
+    }
+
+    /// State-transition function for the block at `height`. Accounts for the
+    /// slots missed since the parent block, releases validators and stake
+    /// whose jail or unbonding period ends at this height, then validates each
+    /// transaction against the state left by the ones before it and applies
+    /// the valid ones to an in-memory overlay. Nothing is written.
+    fn execute_block(&self, height: u64, timestamp: u64, transactions: Vec<Transaction>, validator: &str) -> BlockExecution<'_> {
+        let mut execution = BlockExecution {
+            state: StateOverlay::new(&self.state),
+            applied: Vec::new(),
+            dropped: Vec::new(),
+        };
+
+        if self.consensus.tracks_downtime() {
+            self.track_downtime(&mut execution.state, height, timestamp, validator);
+        }
+
+        for jailed in self.get_validators().into_iter().filter(|v| v.status == ValidatorStatus::Jailed && v.jailed_until <= height) {
+            let mut validator = execution.state.validator(&jailed.address).unwrap();
+            validator.status = ValidatorStatus::Inactive;
+            validator.update_status();
+            execution.state.set_validator(&jailed.address, Some(&validator).filter(|v| !v.is_retired()));
+            info!("Validator {} released from jail", jailed.address);
+        }
+
+        for (address, unbonded_from, amount) in execution.state.take_unbonding(height) {
+            let mut account = execution.state.account(&address);
+            account.balance = account.balance.saturating_add(amount); // Unbonding stake is part of the supply, so this cannot overflow
+            execution.state.set_account(&address, &account);
+            if address == unbonded_from {
+                if let Some(mut validator) = execution.state.validator(&address) {
+                    validator.unbonding = validator.unbonding.saturating_sub(amount);
+                    execution.state.set_validator(&address, Some(&validator).filter(|v| !v.is_retired()));
+                }
+            }
+            debug!("Released {} unbonded MOHSIN tokens to {}", amount, address);
+        }
+
+        let mut seen_ids = HashSet::new();
+        for tx in transactions {
+            let already_included = self.transactions
+                .contains_key(tx.id.as_bytes())
+                .expect("Failed to read transaction index");
+            let result = if already_included || seen_ids.contains(&tx.id) {
+                Err(ChainError::DuplicateTransaction(tx.id.clone()))
+            } else {
+                execution.state.checkpoint();
+                self.apply_transaction(&mut execution.state, &tx, height, validator)
+            };
+
+            match result {
+                Ok(()) => {
+                    seen_ids.insert(tx.id.clone());
+                    execution.applied.push(tx);
+                }
+                Err(reason) => {
+                    execution.state.revert(); // A credit can fail after earlier writes
+                    execution.dropped.push((tx, reason));
+                }
+            }
+        }
+
+        execution
+    }
+
+    /// Every slot between the parent block and the block at `height` was
+    /// missed by its proposer. A validator that misses `max_missed_slots` in a
+    /// row is slashed; producing a block resets its count. A gap longer than
+    /// that means no validator was producing, so it is not held against anyone.
+    fn track_downtime(&self, state: &mut StateOverlay, height: u64, timestamp: u64, block_validator: &str) {
+        let parent = match height.checked_sub(1).and_then(|index| self.get_block(index)) {
+            Some(parent) => parent,
+            None => return,
+        };
+        let missed = pos::slot_at(parent.timestamp) + 1..pos::slot_at(timestamp);
+        if missed.end.saturating_sub(missed.start) > self.config.max_missed_slots {
+            info!("No blocks for {} slots before block {}, not counting them as missed", missed.end - missed.start, height);
+        } else {
+            let validators = self.get_validators();
+            for slot in missed {
+                let Some(proposer) = pos::select_validator(&validators, &parent.hash, slot) else { continue };
+                let mut validator = state.validator(&proposer.address).unwrap();
+                validator.missed_slots += 1;
+                if validator.missed_slots >= self.config.max_missed_slots && validator.status != ValidatorStatus::Jailed {
+                    Self::slash(state, &mut validator, SlashReason::Downtime, height, height);
+                }
+                state.set_validator(&proposer.address, Some(&validator));
+            }
+        }
+
+        if let Some(mut validator) = state.validator(block_validator).filter(|v| v.missed_slots > 0) {
+            validator.missed_slots = 0;
+            state.set_validator(block_validator, Some(&validator));
+        }
+    }
+
+    /// Burns `reason.slash_percent()` of the validator's own stake, bonded
+    /// and unbonding, jails it and records the event. The caller stores `validator`.
+    fn slash(state: &mut StateOverlay, validator: &mut Validator, reason: SlashReason, offence_height: u64, height: u64) {
+        let slashed_bonded = pos::percent_of(validator.stake, reason.slash_percent());
+        validator.stake -= slashed_bonded;
+        let slashed_unbonding = state.slash_unbonding(&validator.address, &validator.address, reason.slash_percent());
+        validator.unbonding = validator.unbonding.saturating_sub(slashed_unbonding);
+        let slashed = slashed_bonded.saturating_add(slashed_unbonding);
+        let mut supply = state.supply();
+        supply.burned = supply.burned.saturating_add(slashed);
+        state.set_supply(&supply);
+        validator.status = ValidatorStatus::Jailed;
+        validator.jailed_until = height + pos::JAIL_PERIOD;
+        validator.missed_slots = 0;
+        state.add_slash_event(&SlashEvent {
+            validator: validator.address.clone(),
+            reason,
+            height: offence_height,
+            slashed,
+            jailed_until: validator.jailed_until,
+        });
+        warn!("Validator {} slashed {} MOHSIN tokens for {} and jailed until block {}", validator.address, slashed, reason.as_str(), validator.jailed_until);
+    }
+
+    /// Checks one transaction against `state` and, only if every check passes, applies it.
+    fn apply_transaction(&self, state: &mut StateOverlay, tx: &Transaction, height: u64, block_validator: &str) -> Result<(), ChainError> {
+        tx.verify_signature()?;
+        if !crypto::is_address(&tx.recipient) {
+            return Err(ChainError::InvalidRecipient("Recipient must be an address of 40 lowercase hex digits"));
+        }
+
+        let mut sender = state.account(&tx.sender);
+        if tx.nonce < sender.nonce {
+            return Err(ChainError::NonceAlreadyUsed { expected: sender.nonce, found: tx.nonce });
+        }
+        if tx.nonce > sender.nonce {
+            return Err(ChainError::NonceTooHigh { expected: sender.nonce, found: tx.nonce });
+        }
+        let debit = tx.debit()?;
+        if sender.balance < debit {
+            return Err(ChainError::InsufficientFunds { available: sender.balance, required: debit });
+        }
+        if matches!(tx.kind, TransactionKind::Stake | TransactionKind::Unstake | TransactionKind::Delegate | TransactionKind::Mint) && tx.amount == 0 {
+            return Err(ChainError::InvalidAmount("Amount must be positive"));
+        }
+
+        match tx.kind {
+            TransactionKind::Transfer => {}
+            TransactionKind::Stake => {
+                if tx.recipient != tx.sender {
+                    return Err(ChainError::InvalidRecipient("Stake recipient must be the sender"));
+                }
+            }
+            TransactionKind::Delegate => {
+                if tx.recipient == tx.sender {
+                    return Err(ChainError::InvalidRecipient("Use a stake transaction to stake on yourself"));
+                }
+                state.validator(&tx.recipient).ok_or_else(|| ChainError::ValidatorNotFound(tx.recipient.clone()))?;
+            }
+            TransactionKind::Unstake => {
+                let validator = state.validator(&tx.recipient).ok_or_else(|| ChainError::ValidatorNotFound(tx.recipient.clone()))?;
+                let bonded = if tx.recipient == tx.sender { validator.stake } else { state.delegation(&tx.sender, &tx.recipient) };
+                if bonded < tx.amount {
+                    return Err(ChainError::InsufficientStake { bonded, requested: tx.amount });
+                }
+            }
+            TransactionKind::Evidence => {
+                let evidence = tx.evidence.as_ref().ok_or(ChainError::MissingEvidence)?;
+                evidence.verify()?;
+                if evidence.first.validator != tx.recipient {
+                    return Err(ChainError::InvalidEvidence("blocks are not from the recipient"));
+                }
+                state.validator(&tx.recipient).ok_or_else(|| ChainError::ValidatorNotFound(tx.recipient.clone()))?;
+                if state.slash_event(evidence.first.index, &tx.recipient, SlashReason::DoubleSign).is_some() {
+                    return Err(ChainError::AlreadySlashed);
+                }
+            }
+            TransactionKind::Mint => {
+                if !self.is_mint_authority(&tx.sender) {
+                    return Err(ChainError::NotMintAuthority);
+                }
+                if !state.supply().can_issue(tx.amount) {
+                    return Err(ChainError::InvalidAmount("Amount would overflow the token supply"));
+                }
+            }
+        }
+
+        sender.nonce += 1;
+        sender.balance -= debit;
+        state.set_account(&tx.sender, &sender);
+
+        match tx.kind {
+            TransactionKind::Transfer => {
+                let mut recipient = state.account(&tx.recipient);
+                recipient.balance = credit(recipient.balance, tx.amount)?;
+                state.set_account(&tx.recipient, &recipient);
+            }
+            TransactionKind::Stake => {
+                let mut validator = state.validator(&tx.sender).unwrap_or_else(|| Validator::new(&tx.sender));
+                validator.stake = credit(validator.stake, tx.amount)?;
+                validator.update_status();
+                state.set_validator(&tx.sender, Some(&validator));
+            }
+            TransactionKind::Delegate => {
+                let mut validator = state.validator(&tx.recipient).unwrap();
+                validator.delegated = credit(validator.delegated, tx.amount)?;
+                validator.update_status();
+                state.set_validator(&tx.recipient, Some(&validator));
+                let delegated = state.delegation(&tx.sender, &tx.recipient);
+                state.set_delegation(&tx.sender, &tx.recipient, credit(delegated, tx.amount)?);
+            }
+            TransactionKind::Unstake => {
+                let mut validator = state.validator(&tx.recipient).unwrap();
+                if tx.recipient == tx.sender {
+                    validator.stake -= tx.amount;
+                    validator.unbonding = credit(validator.unbonding, tx.amount)?;
+                } else {
+                    validator.delegated -= tx.amount;
+                    let delegated = state.delegation(&tx.sender, &tx.recipient);
+                    state.set_delegation(&tx.sender, &tx.recipient, delegated - tx.amount);
+                }
+                validator.update_status();
+                state.set_validator(&tx.recipient, Some(&validator).filter(|v| !v.is_retired()));
+                state.add_unbonding(height + pos::UNBONDING_PERIOD, &tx.sender, &tx.recipient, tx.amount);
+            }
+            TransactionKind::Evidence => {
+                let mut validator = state.validator(&tx.recipient).unwrap();
+                let offence_height = tx.evidence.as_ref().unwrap().first.index;
+                Self::slash(state, &mut validator, SlashReason::DoubleSign, offence_height, height);
+                state.set_validator(&tx.recipient, Some(&validator));
+            }
+            TransactionKind::Mint => {
+                let mut recipient = state.account(&tx.recipient);
+                recipient.balance = credit(recipient.balance, tx.amount)?;
+                state.set_account(&tx.recipient, &recipient);
+                let mut supply = state.supply();
+                supply.minted = credit(supply.minted, tx.amount)?;
+                state.set_supply(&supply);
+            }
+        }
+
+        let mut fee_recipient = state.account(block_validator);
+        fee_recipient.balance = credit(fee_recipient.balance, tx.fee)?;
+        state.set_account(block_validator, &fee_recipient);
+        Ok(())
+    }
+
+    pub fn get_block(&self, index: u64) -> Option<Block> {
+        self.blocks
+            .get(index.to_be_bytes())
+            .expect("Failed to get block from database")
+            .and_then(|value| serde_json::from_slice(&value).ok())
+    }
+
+    pub fn get_account(&self, address: &str) -> Account {
+        self.state.get(StateTree::Accounts, address).unwrap_or_default()
+    }
+
+    pub fn get_balance(&self, address: &str) -> u64 {
+        self.get_account(address).balance
+    }
+
+    /// Next nonce a new transaction from `address` must use: the committed
+    /// account nonce plus the sender's transactions already waiting in the mempool.
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        let account_nonce = self.get_account(address).nonce;
+        let mempool = self.mempool.lock().unwrap();
+        mempool.next_nonce(address, account_nonce)
+    }
+
+    pub fn add_transaction(&self, transaction: Transaction) -> Result<(), ChainError> {
+        transaction.verify_signature()?;
+        if !crypto::is_address(&transaction.recipient) {
+            return Err(ChainError::InvalidRecipient("Recipient must be an address of 40 lowercase hex digits"));
+        }
+        if transaction.kind == TransactionKind::Mint && !self.is_mint_authority(&transaction.sender) {
+            return Err(ChainError::NotMintAuthority);
+        }
+
+        let account = self.get_account(&transaction.sender);
+        let already_included = self.transactions
+            .contains_key(transaction.id.as_bytes())
+            .expect("Failed to read transaction index");
+        if already_included {
+            return Err(ChainError::DuplicateTransaction(transaction.id.clone()));
+        }
+        let height = self.next_block_index() - 1;
+        let now = Utc::now().timestamp() as u64;
+
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut dropped = mempool.expire(now);
+        let inserted = mempool.insert(transaction.clone(), &account, now);
+        if let Ok(replaced) = &inserted {
+            dropped.extend(replaced.iter().cloned());
+        }
+        for (tx, reason) in &dropped {
+            self.drop_transaction(&mut mempool, tx, reason, height);
+        }
+        inserted?;
+
+        debug!("Transaction added: {:?}", transaction); // Log added transaction
+        drop(mempool);
+        let _ = self.events.send(ChainEvent::PendingTransaction(Box::new(transaction)));
+        Ok(())
+    }
+
+    /// Produces a block whenever the consensus engine lets one of
+    /// `validator_keys` do so, and gossips it to peers.
+    pub fn start_mining(&self, network: Arc<Network>, validator_keys: Vec<String>) {
+        let blockchain = self.clone();
+        let our_validators: HashMap<String, String> = validator_keys
+            .into_iter()
+            .map(|key| (crypto::address_from_public_key(&crypto::public_key_from_private_key(&key).unwrap()).unwrap(), key))
+            .collect();
+        let addresses: Vec<String> = our_validators.keys().cloned().collect();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(blockchain.consensus.wait()).await;
+
+                let timestamp = Utc::now().timestamp() as u64;
+                let Some(producer) = blockchain.consensus.producer(&blockchain, &addresses, timestamp) else { continue };
+                let validator_key = our_validators[&producer].clone();
+
+                // Sealing may take a while under proof-of-work
+                let miner = blockchain.clone();
+                let mined = tokio::task::spawn_blocking(move || miner.mine_block(&validator_key, timestamp))
+                    .await
+                    .expect("Mining task panicked");
+                let Some(block) = mined else { continue };
+                match blockchain.add_block(block.clone()) {
+                    Ok(()) => network.broadcast_block(&block),
+                    Err(e) => warn!("Discarding mined block {}: {}", block.index, e),
+                }
+            }
+        });
+    }
+
+    /// Builds, seals and signs the next block. `None` if it went stale while being sealed.
+    fn mine_block(&self, validator_key: &str, timestamp: u64) -> Option<Block> {
+        let public_key = crypto::public_key_from_private_key(validator_key).unwrap();
+        let validator = crypto::address_from_public_key(&public_key).unwrap();
+        let block_index = self.next_block_index();
+
+        let previous_hash = self.parent_hash(block_index).expect("Missing parent of the next block");
+
+        // Only include transactions that apply cleanly on top of the current state
+        let execution = self.execute_block(block_index, timestamp, self.select_block_transactions(), &validator);
+        if !execution.dropped.is_empty() {
+            let mut mempool = self.mempool.lock().unwrap();
+            for (tx, reason) in &execution.dropped {
+                warn!("Dropping transaction {} from block {}: {}", tx.id, block_index, reason);
+                self.drop_transaction(&mut mempool, tx, reason, block_index - 1);
+            }
+        }
+        let transactions_to_include = execution.applied;
+        let reward = self.config.monetary.block_reward(block_index, transactions_to_include.len());
+
+        let merkle_root = merkle::merkle_root(&transactions_to_include.iter().map(Transaction::hash).collect::<Vec<_>>());
+        let mut block = Block {
+            index: block_index,
+            previous_hash,
+            timestamp,
+            merkle_root,
+            transactions: transactions_to_include,
+            proof: 0,
+            difficulty: 0,
+            reward,
+            hash: String::new(),
+            validator,
+            public_key,
+            signature: String::new(),
+        };
+        if !self.consensus.seal(self, &mut block) {
+            return None;
+        }
+        block.signature = crypto::sign(validator_key, block.hash.as_bytes()).unwrap();
+        Some(block)
+    }
+
+    /// Picks mempool transactions by fee per byte, see `Mempool::select`.
+    /// They stay in the mempool until a block including them is added.
+    fn select_block_transactions(&self) -> Vec<Transaction> {
+        let mempool = self.mempool.lock().unwrap();
+        mempool.select(self.config.max_block_transactions, self.config.max_block_bytes)
+    }
+
+    pub fn min_fee(&self) -> u64 {
+        self.config.mempool.min_fee
+    }
+
+    pub fn monetary_policy(&self) -> &MonetaryPolicy {
+        &self.config.monetary
+    }
+
+    fn is_mint_authority(&self, address: &str) -> bool {
+        self.config.monetary.mint_authority.as_deref() == Some(address)
+    }
+
+    /// Tokens issued and burned up to the tip.
+    pub fn get_supply(&self) -> Supply {
+        self.state.supply()
+    }
+
+    /// Counts, pending transactions and recent drops, for `/mempool`.
+    pub fn mempool_status(&self) -> MempoolStatus {
+        self.mempool.lock().unwrap().status()
+    }
+
+    /// Validators sorted by address, the order `pos::select_validator` expects.
+    pub fn get_validators(&self) -> Vec<Validator> {
+        self.state.tree(StateTree::Validators)
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry.expect("Failed to read validator from database");
+                serde_json::from_slice(&value).expect("Corrupt validator in database")
+            })
+            .collect()
+    }
+
+    pub fn get_validator(&self, address: &str) -> Option<Validator> {
+        self.state.get(StateTree::Validators, address)
+    }
+
+    /// Every slashing event, oldest offence first.
+    pub fn get_slash_events(&self) -> Vec<SlashEvent> {
+        self.state.tree(StateTree::Slashes)
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry.expect("Failed to read slash event from database");
+                serde_json::from_slice(&value).expect("Corrupt slash event in database")
+            })
+            .collect()
+    }
+
+    /// Evidence of double-signing if `block` conflicts with our block at the
+    /// same height and slot from the same validator.
+    pub fn find_double_sign(&self, block: &Block) -> Option<DoubleSignEvidence> {
+        let ours = self.get_block(block.index)?;
+        let evidence = DoubleSignEvidence { first: ours.header(), second: block.header() };
+        evidence.verify().ok()?;
+        Some(evidence)
+    }
+
+    /// Stake-weighted proposer for `slot` on top of the block hashed `previous_hash`.
+    pub fn select_proposer(&self, previous_hash: &str, slot: u64) -> Option<Validator> {
+        pos::select_validator(&self.get_validators(), previous_hash, slot)
+    }
+
+}
+
+/// `value + amount` for a balance, stake or supply credit, or `InvalidAmount` if it overflows.
+fn credit(value: u64, amount: u64) -> Result<u64, ChainError> {
+    value.checked_add(amount).ok_or(ChainError::InvalidAmount("Amount overflows the credited balance"))
+}
+
+/// Chains, signed transactions and blocks for tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub(crate) fn key(name: &str) -> String {
+        encode(Sha256::digest(name))
+    }
+
+    pub(crate) fn address(key: &str) -> String {
+        crypto::address_from_public_key(&crypto::public_key_from_private_key(key).unwrap()).unwrap()
+    }
+
+    /// The bundled genesis, with 1000 tokens for `key("alice")`.
+    pub(crate) fn chain() -> Blockchain {
+        let mut genesis = Genesis::default();
+        genesis.balances.insert(address(&key("alice")), 1000);
+        Blockchain::temporary(&genesis)
+    }
+
+    pub(crate) fn signed(key: &str, kind: TransactionKind, recipient: &str, amount: u64, nonce: u64) -> Transaction {
+        let public_key = crypto::public_key_from_private_key(key).unwrap();
+        let sender = crypto::address_from_public_key(&public_key).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            kind,
+            sender,
+            recipient: recipient.to_string(),
+            amount,
+            fee: 1,
+            nonce,
+            public_key,
+            signature: String::new(),
+            evidence: None,
+        };
+        tx.sign(key).unwrap();
+        tx
+    }
+
+    pub(crate) fn transfer(key: &str, recipient: &str, amount: u64, nonce: u64) -> Transaction {
+        signed(key, TransactionKind::Transfer, recipient, amount, nonce)
+    }
+
+    pub(crate) fn proposer() -> String {
+        address(&pos::dev_validator_key(0))
+    }
+
+    /// Slot of the first block the tests produce, far enough back that later ones are not in the future.
+    pub(crate) fn first_slot() -> u64 {
+        pos::slot_at(Utc::now().timestamp() as u64) - 100
+    }
+
+    /// Key of a development validator, or of `key("alice")`, who may stake.
+    pub(crate) fn validator_key(validator: &str) -> String {
+        (0..pos::DEV_VALIDATORS).map(pos::dev_validator_key).chain([key("alice")]).find(|key| address(key) == validator).unwrap()
+    }
+
+    /// Submits `transactions` and adds the block for `slot`, produced by the slot's proposer.
+    pub(crate) fn produce(chain: &Blockchain, slot: u64, transactions: Vec<Transaction>) -> Block {
+        for tx in transactions {
+            chain.add_transaction(tx).unwrap();
+        }
+        let tip = chain.get_last_block().unwrap();
+        let proposer = chain.select_proposer(&tip.hash, slot).unwrap();
+        let block = chain.mine_block(&validator_key(&proposer.address), slot * BLOCK_TIME.as_secs()).unwrap();
+        chain.add_block(block.clone()).unwrap();
+        block
+    }
+
+    /// Hashes and signs `block` again with `key`, as its producer.
+    pub(crate) fn resign(block: &mut Block, key: &str) {
+        block.public_key = crypto::public_key_from_private_key(key).unwrap();
+        block.validator = address(key);
+        block.hash = block.header().calculate_hash();
+        block.signature = crypto::sign(key, block.hash.as_bytes()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::*;
+
+    /// Every entry of the state trees and the chain's trees. Undo records are
+    /// left out: they list the same writes in no fixed order.
+    fn contents(chain: &Blockchain) -> Vec<Vec<(sled::IVec, sled::IVec)>> {
+        let mut trees: Vec<&Tree> = chain.state.all().iter().collect();
+        trees.extend([&chain.blocks, &chain.transactions, &chain.block_hashes, &chain.address_transactions]);
+        trees.iter().map(|tree| tree.iter().map(Result::unwrap).collect()).collect()
+    }
+
+    fn header(proof: u64, difficulty: u32) -> BlockHeader {
+        BlockHeader {
+            index: 1,
+            previous_hash: "0".repeat(64),
+            timestamp: 1_700_000_000,
+            merkle_root: "0".repeat(64),
+            proof,
+            difficulty,
+            reward: 8,
+            hash: String::new(),
+            validator: "ab".repeat(20),
+            public_key: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn header_fields_do_not_run_into_each_other() {
+        assert_ne!(header(12, 3).calculate_hash(), header(1, 23).calculate_hash());
+        assert_ne!(header(1, 23).calculate_hash(), header(123, 0).calculate_hash());
+    }
+
+    #[test]
+    fn transfer_moves_amount_and_pays_fee_to_proposer() {
+        let chain = chain();
+        let (alice, bob) = (key("alice"), address(&key("bob")));
+        let mut state = StateOverlay::new(&chain.state);
+        chain.apply_transaction(&mut state, &transfer(&alice, &bob, 100, 0), 1, &proposer()).unwrap();
+        let sender = state.account(&address(&alice));
+        assert_eq!((sender.balance, sender.nonce), (899, 1));
+        assert_eq!(state.account(&bob).balance, 100);
+        assert_eq!(state.account(&proposer()).balance, chain.get_balance(&proposer()) + 1);
+    }
+
+    #[test]
+    fn nonce_must_be_the_next_one() {
+        let chain = chain();
+        let (alice, bob) = (key("alice"), address(&key("bob")));
+        let mut state = StateOverlay::new(&chain.state);
+        let result = chain.apply_transaction(&mut state, &transfer(&alice, &bob, 1, 1), 1, &proposer());
+        assert_eq!(result, Err(ChainError::NonceTooHigh { expected: 0, found: 1 }));
+        chain.apply_transaction(&mut state, &transfer(&alice, &bob, 1, 0), 1, &proposer()).unwrap();
+        let result = chain.apply_transaction(&mut state, &transfer(&alice, &bob, 1, 0), 1, &proposer());
+        assert_eq!(result, Err(ChainError::NonceAlreadyUsed { expected: 1, found: 0 }));
+    }
+
+    #[test]
+    fn rejected_transaction_writes_nothing() {
+        let chain = chain();
+        let (alice, bob) = (key("alice"), address(&key("bob")));
+        let mut state = StateOverlay::new(&chain.state);
+        let result = chain.apply_transaction(&mut state, &transfer(&alice, &bob, 1000, 0), 1, &proposer());
+        assert_eq!(result, Err(ChainError::InsufficientFunds { available: 1000, required: 1001 }));
+        assert!(state.writes().is_empty());
+    }
+
+    #[test]
+    fn signature_covers_the_amount() {
+        let chain = chain();
+        let mut tx = transfer(&key("alice"), &address(&key("bob")), 1, 0);
+        tx.amount = 500;
+        let mut state = StateOverlay::new(&chain.state);
+        assert_eq!(chain.apply_transaction(&mut state, &tx, 1, &proposer()), Err(ChainError::InvalidSignature));
+    }
+
+    #[test]
+    fn stake_locks_balance_and_activates_validator() {
+        let chain = chain();
+        let alice = key("alice");
+        let mut state = StateOverlay::new(&chain.state);
+        let wrong_recipient = signed(&alice, TransactionKind::Stake, &proposer(), 150, 0);
+        assert!(matches!(chain.apply_transaction(&mut state, &wrong_recipient, 1, &proposer()), Err(ChainError::InvalidRecipient(_))));
+        let zero = signed(&alice, TransactionKind::Stake, &address(&alice), 0, 0);
+        assert!(matches!(chain.apply_transaction(&mut state, &zero, 1, &proposer()), Err(ChainError::InvalidAmount(_))));
+
+        chain.apply_transaction(&mut state, &signed(&alice, TransactionKind::Stake, &address(&alice), 150, 0), 1, &proposer()).unwrap();
+        let validator = state.validator(&address(&alice)).unwrap();
+        assert_eq!((validator.stake, validator.status), (150, ValidatorStatus::Active));
+        assert_eq!(state.account(&address(&alice)).balance, 849);
+    }
+
+    #[test]
+    fn delegate_and_unstake_track_the_delegation() {
+        let chain = chain();
+        let alice = key("alice");
+        let mut state = StateOverlay::new(&chain.state);
+        let unknown = signed(&alice, TransactionKind::Delegate, &address(&key("bob")), 50, 0);
+        assert!(matches!(chain.apply_transaction(&mut state, &unknown, 1, &proposer()), Err(ChainError::ValidatorNotFound(_))));
+
+        let validator = proposer();
+        chain.apply_transaction(&mut state, &signed(&alice, TransactionKind::Delegate, &validator, 50, 0), 1, &validator).unwrap();
+        assert_eq!(state.delegation(&address(&alice), &validator), 50);
+        assert_eq!(state.validator(&validator).unwrap().delegated, 50);
+
+        let too_much = signed(&alice, TransactionKind::Unstake, &validator, 51, 1);
+        let result = chain.apply_transaction(&mut state, &too_much, 1, &validator);
+        assert_eq!(result, Err(ChainError::InsufficientStake { bonded: 50, requested: 51 }));
+        chain.apply_transaction(&mut state, &signed(&alice, TransactionKind::Unstake, &validator, 20, 1), 1, &validator).unwrap();
+        assert_eq!(state.delegation(&address(&alice), &validator), 30);
+        assert_eq!(state.validator(&validator).unwrap().delegated, 30);
+
+        chain.commit(&state.writes(), &[]);
+        let mut state = StateOverlay::new(&chain.state);
+        assert!(state.take_unbonding(pos::UNBONDING_PERIOD).is_empty());
+        assert_eq!(state.take_unbonding(1 + pos::UNBONDING_PERIOD), vec![(address(&alice), validator, 20)]);
+    }
+
+    #[test]
+    fn only_the_mint_authority_mints() {
+        let chain = chain();
+        let mut state = StateOverlay::new(&chain.state);
+        let mint = signed(&key("alice"), TransactionKind::Mint, &address(&key("bob")), 10, 0);
+        assert_eq!(chain.apply_transaction(&mut state, &mint, 1, &proposer()), Err(ChainError::NotMintAuthority));
+    }
+
+    #[test]
+    fn id_is_derived_from_the_signed_contents() {
+        let tx = transfer(&key("alice"), &address(&key("bob")), 1, 0);
+        assert_eq!(tx.id, tx.compute_id());
+        tx.verify_signature().unwrap();
+
+        let renamed = Transaction { id: "mine".to_string(), ..tx.clone() };
+        assert_eq!(renamed.verify_signature(), Err(ChainError::IdMismatch { expected: tx.id.clone() }));
+        let mut unnamed = Transaction { id: String::new(), ..tx.clone() };
+        unnamed.fill_id();
+        assert_eq!(unnamed.id, tx.id);
+        let uppercase = Transaction { signature: tx.signature.to_uppercase(), ..tx.clone() };
+        assert_eq!(uppercase.compute_id(), tx.id);
+    }
+
+    #[test]
+    fn reorganization_leaves_the_state_of_the_winning_branch() {
+        let (ours, theirs) = (chain(), chain());
+        let (alice, slot) = (key("alice"), first_slot());
+        let to_bob = transfer(&alice, &address(&key("bob")), 100, 0);
+        produce(&ours, slot, vec![to_bob.clone()]);
+        produce(&ours, slot + 1, vec![signed(&alice, TransactionKind::Delegate, &proposer(), 50, 1)]);
+        produce(&theirs, slot, vec![transfer(&alice, &address(&key("carol")), 10, 0)]);
+        produce(&theirs, slot + 1, vec![signed(&alice, TransactionKind::Stake, &address(&alice), 150, 1)]);
+        produce(&theirs, slot + 2, vec![]);
+
+        ours.reorganize(theirs.get_blocks(1, 10)).unwrap();
+        assert!(contents(&ours) == contents(&theirs));
+        assert_eq!(ours.chain_work(), theirs.chain_work());
+        assert_eq!(ours.next_block_index(), 4);
+        assert!(ours.get_dropped_transaction(&to_bob.id).is_some()); // Its nonce is used on the new branch
+    }
+
+    #[test]
+    fn failed_reorganization_restores_the_previous_chain() {
+        let (ours, theirs) = (chain(), chain());
+        let (alice, slot) = (key("alice"), first_slot());
+        produce(&ours, slot, vec![transfer(&alice, &address(&key("bob")), 100, 0)]);
+        produce(&ours, slot + 1, vec![]);
+        produce(&theirs, slot, vec![transfer(&alice, &address(&key("carol")), 10, 0)]);
+        produce(&theirs, slot + 1, vec![]);
+        let mut branch = theirs.get_blocks(1, 10);
+        let mut last = produce(&theirs, slot + 2, vec![]);
+        last.reward += 1; // Only applying the block shows this
+        let producer = validator_key(&last.validator);
+        resign(&mut last, &producer);
+        branch.push(last);
+
+        let before = contents(&ours);
+        let result = ours.reorganize(branch);
+        assert!(matches!(result, Err(BlockError::WrongReward { .. })));
+        assert!(contents(&ours) == before);
+    }
+
+    #[test]
+    fn reorganization_checks_every_header_before_rolling_back() {
+        let (ours, theirs) = (chain(), chain());
+        let slot = first_slot();
+        produce(&ours, slot, vec![]);
+        produce(&theirs, slot, vec![transfer(&key("alice"), &address(&key("carol")), 10, 0)]);
+        let last = produce(&theirs, slot + 1, vec![]);
+        let before = contents(&ours);
+
+        let mut forged = last.clone();
+        resign(&mut forged, &key("mallory"));
+        let result = ours.reorganize(vec![theirs.get_block(1).unwrap(), forged]);
+        assert_eq!(result, Err(BlockError::InvalidSeal("Block producer is not a validator")));
+
+        let mut tampered = last.clone();
+        tampered.reward += 1;
+        let result = ours.reorganize(vec![theirs.get_block(1).unwrap(), tampered]);
+        assert_eq!(result, Err(BlockError::HashMismatch));
+        assert!(contents(&ours) == before);
+    }
+
+    /// Two different blocks `key` signed for the same height and slot.
+    fn double_sign(key: &str, index: u64) -> DoubleSignEvidence {
+        let mut first = Genesis::default().block();
+        first.index = index;
+        resign(&mut first, key);
+        let mut second = first.clone();
+        second.merkle_root = "1".repeat(64);
+        resign(&mut second, key);
+        DoubleSignEvidence { first: first.header(), second: second.header() }
+    }
+
+    #[test]
+    fn double_signing_slashes_bonded_and_unbonding_stake() {
+        let chain = chain();
+        let (offender_key, alice) = (pos::dev_validator_key(2), key("alice"));
+        let offender = address(&offender_key);
+        let mut state = StateOverlay::new(&chain.state);
+        chain.apply_transaction(&mut state, &signed(&offender_key, TransactionKind::Unstake, &offender, 100, 0), 1, &proposer()).unwrap();
+
+        let mut report = signed(&alice, TransactionKind::Evidence, &offender, 0, 0);
+        report.evidence = Some(double_sign(&offender_key, 1));
+        report.sign(&alice).unwrap();
+        chain.apply_transaction(&mut state, &report, 1, &proposer()).unwrap();
+
+        let slashed = state.validator(&offender).unwrap();
+        assert_eq!((slashed.stake, slashed.unbonding, slashed.status), (180, 90, ValidatorStatus::Jailed));
+        assert_eq!(slashed.jailed_until, 1 + pos::JAIL_PERIOD);
+        assert_eq!(state.supply().burned, 30);
+        assert_eq!(state.slash_event(1, &offender, SlashReason::DoubleSign).unwrap().slashed, 30);
+
+        let mut again = signed(&alice, TransactionKind::Evidence, &offender, 0, 1);
+        again.evidence = report.evidence.clone();
+        again.sign(&alice).unwrap();
+        assert_eq!(chain.apply_transaction(&mut state, &again, 1, &proposer()), Err(ChainError::AlreadySlashed));
+    }
+
+    #[test]
+    fn downtime_slashes_bonded_and_unbonding_stake() {
+        let mut genesis = Genesis::default();
+        genesis.consensus.max_missed_slots = 2;
+        for validator in &mut genesis.validators {
+            validator.stake = 100_000;
+        }
+        let chain = Blockchain::temporary(&genesis);
+        let slot = first_slot();
+        let parent = produce(&chain, slot, vec![]);
+
+        // Every validator has missed a slot already and unbonds part of its stake
+        let mut state = StateOverlay::new(&chain.state);
+        for key in (0..pos::DEV_VALIDATORS).map(pos::dev_validator_key) {
+            let mut validator = state.validator(&address(&key)).unwrap();
+            validator.missed_slots = 1;
+            state.set_validator(&address(&key), Some(&validator));
+            chain.apply_transaction(&mut state, &signed(&key, TransactionKind::Unstake, &address(&key), 10_000, 0), 1, &proposer()).unwrap();
+        }
+        chain.commit(&state.writes(), &[]);
+
+        // The block after the parent skips one slot
+        let mut state = StateOverlay::new(&chain.state);
+        let missed_by = chain.select_proposer(&parent.hash, slot + 1).unwrap().address;
+        chain.track_downtime(&mut state, 2, (slot + 2) * BLOCK_TIME.as_secs(), &address(&key("alice")));
+
+        let slashed = state.validator(&missed_by).unwrap();
+        assert_eq!((slashed.stake, slashed.unbonding, slashed.status), (89_100, 9_900, ValidatorStatus::Jailed));
+        assert_eq!(slashed.missed_slots, 0);
+        assert_eq!(state.supply().burned, 1_000);
+        assert_eq!(state.slash_event(2, &missed_by, SlashReason::Downtime).unwrap().slashed, 1_000);
+        let others = chain.get_validators().into_iter().filter(|v| v.address != missed_by);
+        assert!(others.into_iter().all(|v| state.validator(&v.address).unwrap().status == ValidatorStatus::Active));
+    }
+
+    /// `block` changed by `change`, hashed and signed again by `key`.
+    fn changed(block: &Block, key: &str, change: impl FnOnce(&mut Block)) -> Block {
+        let mut block = block.clone();
+        change(&mut block);
+        resign(&mut block, key);
+        block
+    }
+
+    #[test]
+    fn invalid_blocks_are_rejected() {
+        let chain = chain();
+        let slot = first_slot();
+        let parent = produce(&chain, slot, vec![]);
+        chain.add_transaction(transfer(&key("alice"), &address(&key("bob")), 1, 0)).unwrap();
+        let expected = chain.select_proposer(&parent.hash, slot + 2).unwrap().address;
+        let producer = validator_key(&expected);
+        let block = chain.mine_block(&producer, (slot + 2) * BLOCK_TIME.as_secs()).unwrap();
+        let other = (0..pos::DEV_VALIDATORS).map(pos::dev_validator_key).find(|key| address(key) != expected).unwrap();
+        let rejected = |block: Block| chain.add_block(block).unwrap_err();
+
+        assert_eq!(rejected(changed(&block, &producer, |block| block.index = 3)), BlockError::UnexpectedIndex { expected: 2, found: 3 });
+        assert_eq!(rejected(changed(&block, &producer, |block| block.previous_hash = "0".repeat(64))), BlockError::UnknownParent);
+        assert_eq!(rejected(Block { reward: block.reward + 1, ..block.clone() }), BlockError::HashMismatch);
+        let forged = Block { signature: crypto::sign(&key("mallory"), block.hash.as_bytes()).unwrap(), ..block.clone() };
+        assert!(matches!(rejected(forged), BlockError::InvalidSignature(_)));
+        assert_eq!(rejected(changed(&block, &producer, |block| block.timestamp = parent.timestamp - 1)), BlockError::TimestampBeforeParent);
+        assert_eq!(rejected(changed(&block, &producer, |block| block.timestamp = parent.timestamp + 1)), BlockError::InvalidSeal("Slot already has a block"));
+        assert_eq!(rejected(changed(&block, &other, |_| ())), BlockError::NotProposer { expected: Some(expected.clone()) });
+        assert_eq!(rejected(changed(&block, &producer, |block| block.transactions.clear())), BlockError::MerkleRootMismatch);
+        let replayed = changed(&block, &producer, |block| {
+            block.transactions = vec![transfer(&key("alice"), &address(&key("bob")), 1, 5)];
+            block.merkle_root = merkle::merkle_root(&[block.transactions[0].hash()]);
+        });
+        assert!(matches!(rejected(replayed), BlockError::InvalidTransaction { reason, .. } if *reason == ChainError::NonceTooHigh { expected: 0, found: 5 }));
+        let reward = block.reward;
+        assert_eq!(rejected(changed(&block, &producer, |block| block.reward += 1)), BlockError::WrongReward { expected: reward, found: reward + 1 });
+
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn blocks_for_slots_that_have_not_started_are_rejected() {
+        let chain = chain();
+        let tip = chain.get_last_block().unwrap();
+        let now = pos::slot_at(Utc::now().timestamp() as u64);
+        let producer = |slot| validator_key(&chain.select_proposer(&tip.hash, slot).unwrap().address);
+
+        let early = chain.mine_block(&producer(now + 2), (now + 2) * BLOCK_TIME.as_secs()).unwrap();
+        assert_eq!(chain.add_block(early), Err(BlockError::TimestampInFuture));
+        let current = chain.mine_block(&producer(now), now * BLOCK_TIME.as_secs()).unwrap();
+        chain.add_block(current).unwrap();
+    }
+
+    #[test]
+    fn skipped_slots_count_against_their_proposers() {
+        let chain = chain();
+        let slot = first_slot();
+        let parent = produce(&chain, slot, vec![]);
+        let missed: Vec<String> = (slot + 1..slot + 4).map(|slot| chain.select_proposer(&parent.hash, slot).unwrap().address).collect();
+        let block = produce(&chain, slot + 4, vec![]);
+
+        for validator in chain.get_validators() {
+            let expected = if validator.address == block.validator { 0 } else { missed.iter().filter(|address| **address == validator.address).count() };
+            assert_eq!(validator.missed_slots, expected as u64, "{}", validator.address);
+        }
+        let next = produce(&chain, slot + 5, vec![]);
+        assert_eq!(chain.get_validator(&next.validator).unwrap().missed_slots, 0);
+    }
+
+    #[test]
+    fn a_gap_longer_than_max_missed_slots_is_not_downtime() {
+        let chain = chain();
+        let slot = first_slot();
+        produce(&chain, slot, vec![]);
+        produce(&chain, slot + chain.config.max_missed_slots + 2, vec![]);
+        assert!(chain.get_validators().iter().all(|validator| validator.missed_slots == 0));
+    }
+
+    #[test]
+    fn recipient_must_be_an_address() {
+        let chain = chain();
+        let (alice, bob) = (key("alice"), address(&key("bob")));
+        let mut state = StateOverlay::new(&chain.state);
+        for recipient in [format!("{}:{:020}:{:06}", bob, 1, 0), bob.to_uppercase(), bob[..38].to_string()] {
+            let tx = transfer(&alice, &recipient, 1, 0);
+            assert!(matches!(chain.add_transaction(tx.clone()), Err(ChainError::InvalidRecipient(_))));
+            assert!(matches!(chain.apply_transaction(&mut state, &tx, 1, &proposer()), Err(ChainError::InvalidRecipient(_))));
+        }
+    }
+
+    #[test]
+    fn address_history_is_in_chain_order_and_per_address() {
+        let chain = chain();
+        let (alice, bob, carol) = (key("alice"), address(&key("bob")), address(&key("carol")));
+        let slot = first_slot();
+        let first = transfer(&alice, &bob, 1, 0);
+        let second = transfer(&alice, &carol, 1, 1);
+        let third = transfer(&alice, &bob, 1, 2);
+        produce(&chain, slot, vec![first.clone(), second.clone()]);
+        produce(&chain, slot + 1, vec![third.clone()]);
+
+        let ids = |address: &str, from| -> Vec<String> {
+            chain.get_address_transactions(address, from, 10).into_iter().map(|included| included.transaction.id).collect()
+        };
+        assert_eq!(ids(&address(&alice), 0), [first.id.clone(), second.id.clone(), third.id.clone()]);
+        assert_eq!(ids(&bob, 0), [first.id.clone(), third.id.clone()]);
+        assert_eq!(ids(&bob, 2), [third.id]);
+        assert_eq!(ids(&carol, 0), [second.id]);
+        assert!(ids(&format!("{}:", bob), 0).is_empty());
+        assert!(ids(&bob[..38], 0).is_empty());
+    }
+}
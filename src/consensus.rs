@@ -0,0 +1,64 @@
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::blockchain::{Block, Blockchain};
+use crate::error::BlockError;
+use crate::pos::ProofOfStake;
+use crate::pow::ProofOfWork;
+
+/// Decides who produces the next block and what makes a block valid.
+/// Blocks are always signed by their producer; the engine adds its own seal
+/// on top of that.
+pub trait ConsensusEngine: Send + Sync {
+    /// How long `Blockchain::start_mining` waits before its next attempt.
+    fn wait(&self) -> Duration;
+
+    /// Which of `our_validators` may produce a block on top of the current tip
+    /// with `timestamp`, if any.
+    fn producer(&self, blockchain: &Blockchain, our_validators: &[String], timestamp: u64) -> Option<String>;
+
+    /// Fills in `proof`, `difficulty` and `hash`. Returns false if the block
+    /// went stale before it could be sealed.
+    fn seal(&self, blockchain: &Blockchain, block: &mut Block) -> bool;
+
+    /// Checks what the header of `block` and of its `parent` show without
+    /// the state: the seal, and that the producer could be eligible. Every
+    /// block of a branch passes this before any block is rolled back for it;
+    /// `earlier` holds the blocks of the branch below `parent`'s child, whose
+    /// state is not applied yet.
+    fn verify_header(&self, blockchain: &Blockchain, block: &Block, parent: &Block, earlier: &[Block]) -> Result<(), BlockError>;
+
+    /// Checks the seal and producer of `block` against the state at its parent.
+    fn verify(&self, blockchain: &Blockchain, block: &Block) -> Result<(), BlockError>;
+
+    /// Whether proposers are assigned to slots, so that empty slots count as downtime.
+    fn tracks_downtime(&self) -> bool {
+        false
+    }
+
+    /// Weight `block` adds to its chain under fork choice, which prefers the
+    /// chain with the most. One per block by default, so the longest chain wins.
+    fn block_work(&self, _block: &Block) -> u128 {
+        1
+    }
+}
+
+/// Consensus engine named in the genesis file, `pos` or `pow`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsensusKind {
+    #[default]
+    #[serde(rename = "pos")]
+    ProofOfStake,
+    #[serde(rename = "pow")]
+    ProofOfWork,
+}
+
+impl ConsensusKind {
+    pub fn engine(self) -> Arc<dyn ConsensusEngine> {
+        match self {
+            ConsensusKind::ProofOfStake => Arc::new(ProofOfStake),
+            ConsensusKind::ProofOfWork => Arc::new(ProofOfWork),
+        }
+    }
+}
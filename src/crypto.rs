@@ -0,0 +1,73 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hex::{decode, encode};
+use rand::rngs::OsRng;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::error::ChainError;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeyPair {
+    pub private_key: String,
+    pub public_key: String,
+    pub address: String,
+}
+
+pub fn generate_key_pair() -> KeyPair {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let public_key = encode(signing_key.verifying_key().to_bytes());
+    KeyPair {
+        private_key: encode(signing_key.to_bytes()),
+        address: address_from_public_key(&public_key).unwrap(),
+        public_key,
+    }
+}
+
+/// Addresses are the first 20 bytes of the SHA-256 digest of the ed25519 public key, hex encoded.
+pub fn address_from_public_key(public_key: &str) -> Result<String, ChainError> {
+    let verifying_key = parse_public_key(public_key)?;
+    let digest = Sha256::digest(verifying_key.as_bytes());
+    Ok(encode(&digest[..20]))
+}
+
+/// Whether `address` has the form of an address: 40 lowercase hex digits.
+pub fn is_address(address: &str) -> bool {
+    address.len() == 40 && address.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn public_key_from_private_key(private_key: &str) -> Result<String, ChainError> {
+    let signing_key = parse_private_key(private_key)?;
+    Ok(encode(signing_key.verifying_key().to_bytes()))
+}
+
+pub fn sign(private_key: &str, message: &[u8]) -> Result<String, ChainError> {
+    let signing_key = parse_private_key(private_key)?;
+    Ok(encode(signing_key.sign(message).to_bytes()))
+}
+
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> Result<(), ChainError> {
+    let verifying_key = parse_public_key(public_key)?;
+    let bytes: [u8; 64] = decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ChainError::MalformedSignature)?;
+    verifying_key
+        .verify_strict(message, &Signature::from_bytes(&bytes))
+        .map_err(|_| ChainError::InvalidSignature)
+}
+
+fn parse_private_key(private_key: &str) -> Result<SigningKey, ChainError> {
+    let bytes: [u8; 32] = decode(private_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ChainError::MalformedPrivateKey)?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn parse_public_key(public_key: &str) -> Result<VerifyingKey, ChainError> {
+    let bytes: [u8; 32] = decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ChainError::MalformedPublicKey)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| ChainError::MalformedPublicKey)
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+use warp::http::StatusCode;
+
+/// Every way a request to the chain can fail. `code()` is a stable
+/// identifier for clients; `Display` is the message for people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    // Malformed input
+    MalformedPrivateKey,
+    MalformedPublicKey,
+    MalformedSignature,
+    InvalidAmount(&'static str),
+    InvalidRecipient(&'static str),
+    MissingEvidence,
+    IdMismatch { expected: String },
+
+    // Not allowed for this caller
+    Unauthorized,
+    FaucetCooldown { retry_after: u64 },
+
+    // Unknown ids
+    TransactionNotFound(String),
+    BlockNotFound(u64),
+    BlockHashNotFound(String),
+    ValidatorNotFound(String),
+
+    // Conflicts with what the chain already has
+    DuplicateTransaction(String),
+    NonceAlreadyUsed { expected: u64, found: u64 },
+    ReplacementUnderpriced { minimum: u64, found: u64 },
+    ReplacedByFee(String),
+    AlreadyFinalized,
+    ConflictingVote,
+    AlreadySlashed,
+    GenesisMismatch { expected: String, found: Option<String> },
+
+    // Well-formed but not acceptable
+    InvalidSignature,
+    PublicKeyMismatch,
+    InsufficientFunds { available: u64, required: u64 },
+    NonceTooHigh { expected: u64, found: u64 },
+    FeeTooLow { minimum: u64, found: u64 },
+    TooManyPending { limit: usize },
+    MempoolFull,
+    Evicted,
+    Expired,
+    InsufficientStake { bonded: u64, requested: u64 },
+    NotMintAuthority,
+    FaucetLimit { max_amount: u64 },
+    FaucetExhausted { remaining: u64 },
+    FaucetDisabled,
+    SigningDisabled,
+    InvalidEvidence(&'static str),
+    InactiveValidator,
+    NotOnChain,
+    InvalidBlock(BlockError),
+}
+
+impl ChainError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChainError::MalformedPrivateKey => "malformed_private_key",
+            ChainError::MalformedPublicKey => "malformed_public_key",
+            ChainError::MalformedSignature => "malformed_signature",
+            ChainError::InvalidAmount(_) => "invalid_amount",
+            ChainError::InvalidRecipient(_) => "invalid_recipient",
+            ChainError::MissingEvidence => "missing_evidence",
+            ChainError::IdMismatch { .. } => "id_mismatch",
+            ChainError::Unauthorized => "unauthorized",
+            ChainError::FaucetCooldown { .. } => "faucet_cooldown",
+            ChainError::TransactionNotFound(_) => "transaction_not_found",
+            ChainError::BlockNotFound(_) | ChainError::BlockHashNotFound(_) => "block_not_found",
+            ChainError::ValidatorNotFound(_) => "validator_not_found",
+            ChainError::DuplicateTransaction(_) => "duplicate_transaction",
+            ChainError::NonceAlreadyUsed { .. } => "nonce_already_used",
+            ChainError::ReplacementUnderpriced { .. } => "replacement_underpriced",
+            ChainError::ReplacedByFee(_) => "replaced_by_fee",
+            ChainError::AlreadyFinalized => "already_finalized",
+            ChainError::ConflictingVote => "conflicting_vote",
+            ChainError::AlreadySlashed => "already_slashed",
+            ChainError::GenesisMismatch { .. } => "genesis_mismatch",
+            ChainError::InvalidSignature => "invalid_signature",
+            ChainError::PublicKeyMismatch => "public_key_mismatch",
+            ChainError::InsufficientFunds { .. } => "insufficient_funds",
+            ChainError::NonceTooHigh { .. } => "nonce_too_high",
+            ChainError::FeeTooLow { .. } => "fee_too_low",
+            ChainError::TooManyPending { .. } => "too_many_pending",
+            ChainError::MempoolFull => "mempool_full",
+            ChainError::Evicted => "evicted",
+            ChainError::Expired => "expired",
+            ChainError::InsufficientStake { .. } => "insufficient_stake",
+            ChainError::NotMintAuthority => "not_mint_authority",
+            ChainError::FaucetLimit { .. } => "faucet_limit",
+            ChainError::FaucetExhausted { .. } => "faucet_exhausted",
+            ChainError::FaucetDisabled => "faucet_disabled",
+            ChainError::SigningDisabled => "signing_disabled",
+            ChainError::InvalidEvidence(_) => "invalid_evidence",
+            ChainError::InactiveValidator => "inactive_validator",
+            ChainError::NotOnChain => "not_on_chain",
+            ChainError::InvalidBlock(e) => e.code(),
+        }
+    }
+
+    /// 400 for malformed input, 401 without the admin key, 404 for unknown
+    /// ids, 409 for conflicts with the chain, 429 while the faucet cools down,
+    /// 422 for everything else that cannot be accepted.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ChainError::MalformedPrivateKey
+            | ChainError::MalformedPublicKey
+            | ChainError::MalformedSignature
+            | ChainError::InvalidAmount(_)
+            | ChainError::InvalidRecipient(_)
+            | ChainError::MissingEvidence
+            | ChainError::IdMismatch { .. } => StatusCode::BAD_REQUEST,
+            ChainError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ChainError::FaucetCooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ChainError::TransactionNotFound(_)
+            | ChainError::BlockNotFound(_)
+            | ChainError::BlockHashNotFound(_)
+            | ChainError::ValidatorNotFound(_) => StatusCode::NOT_FOUND,
+            ChainError::DuplicateTransaction(_)
+            | ChainError::NonceAlreadyUsed { .. }
+            | ChainError::ReplacementUnderpriced { .. }
+            | ChainError::ReplacedByFee(_)
+            | ChainError::AlreadyFinalized
+            | ChainError::ConflictingVote
+            | ChainError::AlreadySlashed
+            | ChainError::GenesisMismatch { .. } => StatusCode::CONFLICT,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::MalformedPrivateKey => write!(f, "Malformed private key"),
+            ChainError::MalformedPublicKey => write!(f, "Malformed public key"),
+            ChainError::MalformedSignature => write!(f, "Malformed signature"),
+            ChainError::InvalidAmount(reason) | ChainError::InvalidRecipient(reason) => write!(f, "{}", reason),
+            ChainError::MissingEvidence => write!(f, "Evidence transaction without evidence"),
+            ChainError::IdMismatch { expected } => write!(f, "Transaction id must be {}, the hash of its contents and signature", expected),
+            ChainError::Unauthorized => write!(f, "Missing or wrong admin key"),
+            ChainError::FaucetCooldown { retry_after } => write!(f, "Address received from the faucet recently, try again in {} seconds", retry_after),
+            ChainError::TransactionNotFound(id) => write!(f, "Transaction with ID {} not found", id),
+            ChainError::BlockNotFound(index) => write!(f, "Block {} not found", index),
+            ChainError::BlockHashNotFound(hash) => write!(f, "Block with hash {} not found", hash),
+            ChainError::ValidatorNotFound(address) => write!(f, "Validator {} not found", address),
+            ChainError::DuplicateTransaction(id) => write!(f, "Transaction with ID {} already exists", id),
+            ChainError::NonceAlreadyUsed { expected, found } => write!(f, "Nonce {} already used, next nonce is {}", found, expected),
+            ChainError::ReplacementUnderpriced { minimum, found } => write!(f, "Replacement fee {} is below the required {}", found, minimum),
+            ChainError::ReplacedByFee(id) => write!(f, "Replaced by transaction {} with a higher fee", id),
+            ChainError::AlreadyFinalized => write!(f, "Height already finalized"),
+            ChainError::ConflictingVote => write!(f, "Validator already voted for another block at this height"),
+            ChainError::AlreadySlashed => write!(f, "Validator already slashed for this double sign"),
+            ChainError::GenesisMismatch { expected, found: Some(found) } => write!(f, "Peer is on genesis {}, this node on {}", found, expected),
+            ChainError::GenesisMismatch { expected, found: None } => write!(f, "Peer did not say its genesis, this node is on {}", expected),
+            ChainError::InvalidSignature => write!(f, "Invalid signature"),
+            ChainError::PublicKeyMismatch => write!(f, "Public key does not belong to the signer"),
+            ChainError::InsufficientFunds { available, required } => write!(f, "Insufficient funds: {} available, {} required", available, required),
+            ChainError::NonceTooHigh { expected, found } => write!(f, "Nonce {} too high, next nonce is {}", found, expected),
+            ChainError::FeeTooLow { minimum, found } => write!(f, "Fee {} is below the minimum of {}", found, minimum),
+            ChainError::TooManyPending { limit } => write!(f, "Sender already has {} pending transactions", limit),
+            ChainError::MempoolFull => write!(f, "Mempool is full and the fee per byte is too low to evict another transaction"),
+            ChainError::Evicted => write!(f, "Evicted from the full mempool by a transaction paying more per byte"),
+            ChainError::Expired => write!(f, "Transaction waited in the mempool too long"),
+            ChainError::InsufficientStake { bonded, requested } => write!(f, "Cannot unstake {} with {} bonded", requested, bonded),
+            ChainError::NotMintAuthority => write!(f, "Only the mint authority may mint tokens"),
+            ChainError::FaucetLimit { max_amount } => write!(f, "The faucet gives at most {} tokens per request", max_amount),
+            ChainError::FaucetExhausted { remaining } => write!(f, "The faucet has {} tokens left in its budget", remaining),
+            ChainError::FaucetDisabled => write!(f, "This node runs no faucet"),
+            ChainError::SigningDisabled => write!(f, "This node only signs transactions with --dev, sign them with `node sign` instead"),
+            ChainError::InvalidEvidence(reason) => write!(f, "Invalid evidence: {}", reason),
+            ChainError::InactiveValidator => write!(f, "Validator is not active"),
+            ChainError::NotOnChain => write!(f, "Block is not on the chain"),
+            ChainError::InvalidBlock(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl warp::reject::Reject for ChainError {}
+
+impl From<BlockError> for ChainError {
+    fn from(e: BlockError) -> Self {
+        ChainError::InvalidBlock(e)
+    }
+}
+
+/// Why a block, or a branch of blocks, was not added to the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    UnexpectedIndex { expected: u64, found: u64 },
+    UnknownParent, // `previous_hash` is not the hash of the block before it
+    HashMismatch,
+    InvalidSignature(Box<ChainError>),
+    TimestampBeforeParent,
+    TimestampInFuture,
+    NotProposer { expected: Option<String> },
+    InvalidSeal(&'static str), // Rejected by the consensus engine
+    TooManyTransactions,
+    TooLarge,
+    MerkleRootMismatch,
+    InvalidTransaction { id: String, reason: Box<ChainError> },
+    WrongReward { expected: u64, found: u64 },
+    SupplyOverflow, // The reward would take the total issued past u64::MAX
+    EmptyBranch,
+    BranchNotConnected,
+    BranchNotBetter,
+    RevertsFinalizedBlock,
+}
+
+impl BlockError {
+    /// Stable identifier reported to peers.
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlockError::UnexpectedIndex { .. } => "unexpected_index",
+            BlockError::UnknownParent => "unknown_parent",
+            BlockError::HashMismatch => "hash_mismatch",
+            BlockError::InvalidSignature(_) => "invalid_signature",
+            BlockError::TimestampBeforeParent => "timestamp_before_parent",
+            BlockError::TimestampInFuture => "timestamp_in_future",
+            BlockError::NotProposer { .. } => "not_proposer",
+            BlockError::InvalidSeal(_) => "invalid_seal",
+            BlockError::TooManyTransactions => "too_many_transactions",
+            BlockError::TooLarge => "too_large",
+            BlockError::MerkleRootMismatch => "merkle_root_mismatch",
+            BlockError::InvalidTransaction { .. } => "invalid_transaction",
+            BlockError::WrongReward { .. } => "wrong_reward",
+            BlockError::SupplyOverflow => "supply_overflow",
+            BlockError::EmptyBranch => "empty_branch",
+            BlockError::BranchNotConnected => "branch_not_connected",
+            BlockError::BranchNotBetter => "branch_not_better",
+            BlockError::RevertsFinalizedBlock => "reverts_finalized_block",
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::UnexpectedIndex { expected, found } => write!(f, "Expected block {}, got block {}", expected, found),
+            BlockError::UnknownParent => write!(f, "Block does not link to the chain tip"),
+            BlockError::HashMismatch => write!(f, "Block hash does not match its header"),
+            BlockError::InvalidSignature(e) => write!(f, "Invalid block signature: {}", e),
+            BlockError::TimestampBeforeParent => write!(f, "Block timestamp is before its parent's"),
+            BlockError::TimestampInFuture => write!(f, "Block timestamp is in a slot that has not started"),
+            BlockError::NotProposer { expected: Some(expected) } => write!(f, "Block validator is not the proposer for its slot, expected {}", expected),
+            BlockError::NotProposer { expected: None } => write!(f, "No validator is eligible to propose"),
+            BlockError::InvalidSeal(reason) => write!(f, "Invalid block seal: {}", reason),
+            BlockError::TooManyTransactions => write!(f, "Block has too many transactions"),
+            BlockError::TooLarge => write!(f, "Block transactions exceed the size limit"),
+            BlockError::MerkleRootMismatch => write!(f, "Merkle root does not match the transactions"),
+            BlockError::InvalidTransaction { id, reason } => write!(f, "Transaction {} is invalid: {}", id, reason),
+            BlockError::WrongReward { expected, found } => write!(f, "Block reward is {}, expected {}", found, expected),
+            BlockError::SupplyOverflow => write!(f, "Block reward would overflow the token supply"),
+            BlockError::EmptyBranch => write!(f, "Empty branch"),
+            BlockError::BranchNotConnected => write!(f, "Branch does not connect to the chain"),
+            BlockError::BranchNotBetter => write!(f, "Branch is not better than the current chain"),
+            BlockError::RevertsFinalizedBlock => write!(f, "Branch replaces a finalized block"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
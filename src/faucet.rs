@@ -0,0 +1,186 @@
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use sled::Tree;
+use std::sync::Mutex;
+
+use crate::blockchain::Blockchain;
+use crate::crypto;
+use crate::error::ChainError;
+
+/// Header carrying the admin key, which allows `/airdrop_tokens` to mint without limits.
+pub const ADMIN_HEADER: &str = "x-admin-key";
+/// Key of the stored total handed out.
+const SPENT_KEY: &[u8] = b"spent";
+
+/// Limits on what the faucet hands out.
+#[derive(Debug, Clone)]
+pub struct FaucetConfig {
+    pub private_key: String, // Key of the funded account the faucet sends from
+    pub max_amount: u64,     // Largest amount one request may ask for
+    pub cooldown: u64,       // Seconds before an address may receive again
+    pub budget: u64,         // Total the faucet may hand out, across restarts
+}
+
+/// What `/faucet` reports.
+#[derive(Serialize, Debug, Clone)]
+pub struct FaucetStatus {
+    pub address: String,
+    pub balance: u64,
+    pub max_amount: u64,
+    pub cooldown: u64,
+    pub budget: u64,
+    pub spent: u64,
+}
+
+/// Hands out tokens as ordinary transfers from a funded account, so they go
+/// through blocks like any other transaction. Each address may receive once
+/// per cooldown, and the total is capped by the budget.
+pub struct Faucet {
+    config: FaucetConfig,
+    address: String,
+    drips: Tree,      // Address -> Unix time of its last drip (big-endian u64), and SPENT_KEY -> total handed out
+    lock: Mutex<()>, // Serializes drips, so that limits are checked and recorded together
+}
+
+impl Faucet {
+    pub fn new(config: FaucetConfig, blockchain: &Blockchain) -> Result<Self, ChainError> {
+        let address = crypto::address_from_public_key(&crypto::public_key_from_private_key(&config.private_key)?)?;
+        Ok(Faucet {
+            config,
+            address,
+            drips: blockchain.open_local_tree("faucet"),
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn private_key(&self) -> &str {
+        &self.config.private_key
+    }
+
+    pub fn status(&self, blockchain: &Blockchain) -> FaucetStatus {
+        FaucetStatus {
+            address: self.address.clone(),
+            balance: blockchain.get_balance(&self.address),
+            max_amount: self.config.max_amount,
+            cooldown: self.config.cooldown,
+            budget: self.config.budget,
+            spent: self.read(SPENT_KEY),
+        }
+    }
+
+    /// Checks a drip of `amount` to `address` against the limits, runs `send`
+    /// to submit it and records it if that succeeds.
+    pub fn drip<T>(&self, address: &str, amount: u64, now: u64, send: impl FnOnce() -> Result<T, ChainError>) -> Result<T, ChainError> {
+        let _lock = self.lock.lock().unwrap();
+        if amount == 0 {
+            return Err(ChainError::InvalidAmount("Airdrop amount must be positive"));
+        }
+        if amount > self.config.max_amount {
+            return Err(ChainError::FaucetLimit { max_amount: self.config.max_amount });
+        }
+        let last_drip = self.drips.get(address.as_bytes()).expect("Failed to read faucet from database");
+        if let Some(last_drip) = last_drip.map(|value| u64::from_be_bytes(value.as_ref().try_into().unwrap())) {
+            let next_drip = last_drip.saturating_add(self.config.cooldown);
+            if now < next_drip {
+                return Err(ChainError::FaucetCooldown { retry_after: next_drip - now });
+            }
+        }
+        let spent = self.read(SPENT_KEY);
+        let remaining = self.config.budget.saturating_sub(spent);
+        if amount > remaining {
+            return Err(ChainError::FaucetExhausted { remaining });
+        }
+
+        let sent = send()?;
+        self.drips.insert(address.as_bytes(), &now.to_be_bytes()).expect("Failed to write faucet to database");
+        self.drips.insert(SPENT_KEY, &(spent + amount).to_be_bytes()).expect("Failed to write faucet to database");
+        Ok(sent)
+    }
+
+    fn read(&self, key: &[u8]) -> u64 {
+        self.drips
+            .get(key)
+            .expect("Failed to read faucet from database")
+            .map_or(0, |value| u64::from_be_bytes(value.as_ref().try_into().unwrap()))
+    }
+}
+
+/// Deterministic private key of the development faucet, funded by the bundled `genesis.json`.
+pub fn dev_faucet_key() -> String {
+    hex::encode(Sha256::digest("MOHSIN dev faucet"))
+}
+
+/// Whether `given` is the configured admin key. Compares in constant time.
+pub fn is_admin_key(admin_key: &str, given: &str) -> bool {
+    admin_key.len() == given.len() && admin_key.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testing::chain;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn faucet(blockchain: &Blockchain, cooldown: u64) -> Faucet {
+        let config = FaucetConfig { private_key: dev_faucet_key(), max_amount: 100, cooldown, budget: 250 };
+        Faucet::new(config, blockchain).unwrap()
+    }
+
+    fn drip(faucet: &Faucet, address: &str, amount: u64, now: u64) -> Result<(), ChainError> {
+        faucet.drip(address, amount, now, || Ok(()))
+    }
+
+    #[test]
+    fn amount_must_be_positive_and_at_most_the_maximum() {
+        let chain = chain();
+        let faucet = faucet(&chain, 60);
+        assert!(matches!(drip(&faucet, "a", 0, NOW), Err(ChainError::InvalidAmount(_))));
+        assert_eq!(drip(&faucet, "a", 101, NOW), Err(ChainError::FaucetLimit { max_amount: 100 }));
+        drip(&faucet, "a", 100, NOW).unwrap();
+    }
+
+    #[test]
+    fn address_waits_out_the_cooldown() {
+        let chain = chain();
+        let faucet = faucet(&chain, 60);
+        drip(&faucet, "a", 1, NOW).unwrap();
+        assert_eq!(drip(&faucet, "a", 1, NOW + 20), Err(ChainError::FaucetCooldown { retry_after: 40 }));
+        drip(&faucet, "b", 1, NOW + 20).unwrap();
+        drip(&faucet, "a", 1, NOW + 60).unwrap();
+    }
+
+    #[test]
+    fn cooldown_does_not_overflow() {
+        let chain = chain();
+        let faucet = faucet(&chain, u64::MAX);
+        drip(&faucet, "a", 1, NOW).unwrap();
+        assert_eq!(drip(&faucet, "a", 1, NOW + 1), Err(ChainError::FaucetCooldown { retry_after: u64::MAX - NOW - 1 }));
+    }
+
+    #[test]
+    fn budget_caps_the_total_across_restarts() {
+        let chain = chain();
+        let first = faucet(&chain, 0);
+        drip(&first, "a", 100, NOW).unwrap();
+        drip(&first, "b", 100, NOW).unwrap();
+        let restarted = faucet(&chain, 0);
+        assert_eq!(drip(&restarted, "c", 100, NOW), Err(ChainError::FaucetExhausted { remaining: 50 }));
+        drip(&restarted, "c", 50, NOW).unwrap();
+        assert_eq!(restarted.status(&chain).spent, 250);
+    }
+
+    #[test]
+    fn failed_send_records_nothing() {
+        let chain = chain();
+        let faucet = faucet(&chain, 60);
+        let failed = faucet.drip("a", 100, NOW, || Err::<(), _>(ChainError::InvalidSignature));
+        assert_eq!(failed, Err(ChainError::InvalidSignature));
+        assert_eq!(faucet.status(&chain).spent, 0);
+        drip(&faucet, "a", 100, NOW).unwrap();
+    }
+}
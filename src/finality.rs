@@ -0,0 +1,410 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use sled::Tree;
+use log::{debug, info, warn};
+
+use crate::blockchain::{Blockchain, BLOCK_TIME};
+use crate::crypto;
+use crate::error::ChainError;
+use crate::network::Network;
+use crate::pos::ValidatorStatus;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteKind {
+    Prevote,   // The validator has the block at the tip of its chain
+    Precommit, // The validator saw prevotes from more than 2/3 of the stake for the block
+}
+
+impl VoteKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VoteKind::Prevote => "prevote",
+            VoteKind::Precommit => "precommit",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub hash: String, // Hash of the block voted for
+    pub validator: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Vote {
+    /// The bytes covered by the validator's signature.
+    pub fn signing_payload(kind: VoteKind, height: u64, hash: &str) -> Vec<u8> {
+        format!("{}:{}:{}", kind.as_str(), height, hash).into_bytes()
+    }
+
+    pub fn sign(kind: VoteKind, height: u64, hash: &str, private_key: &str) -> Result<Self, ChainError> {
+        let public_key = crypto::public_key_from_private_key(private_key)?;
+        Ok(Vote {
+            kind,
+            height,
+            hash: hash.to_string(),
+            validator: crypto::address_from_public_key(&public_key)?,
+            signature: crypto::sign(private_key, &Self::signing_payload(kind, height, hash))?,
+            public_key,
+        })
+    }
+
+    /// Checks that `public_key` belongs to `validator` and that it signed this vote.
+    pub fn verify_signature(&self) -> Result<(), ChainError> {
+        if crypto::address_from_public_key(&self.public_key)? != self.validator {
+            return Err(ChainError::PublicKeyMismatch);
+        }
+        crypto::verify(&self.public_key, &Self::signing_payload(self.kind, self.height, &self.hash), &self.signature)
+    }
+}
+
+/// Precommits from validators holding more than 2/3 of the stake for one block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinalityCertificate {
+    pub height: u64,
+    pub hash: String,
+    pub precommits: Vec<Vote>,
+}
+
+#[derive(Default)]
+struct VotePool {
+    votes: HashMap<(VoteKind, u64, String), HashMap<String, Vote>>, // (kind, height, hash) -> validator -> vote
+    voted: HashMap<(VoteKind, u64, String), String>,                // (kind, height, validator) -> hash voted for
+    locks: HashMap<String, (u64, String)>,                          // Own validator -> height and hash of its last precommit
+}
+
+impl VotePool {
+    fn record(&mut self, vote: Vote) {
+        self.voted.insert((vote.kind, vote.height, vote.validator.clone()), vote.hash.clone());
+        self.votes
+            .entry((vote.kind, vote.height, vote.hash.clone()))
+            .or_default()
+            .insert(vote.validator.clone(), vote);
+    }
+}
+
+/// Finality gadget on top of block production. Each height gets a single
+/// prevote/precommit round: validators prevote for the block at their tip,
+/// precommit once prevotes from more than 2/3 of the active stake agree on
+/// it, and the block is final once precommits from more than 2/3 of the
+/// active stake do. A validator votes once per height and kind, and a
+/// precommit locks it on the block until finality passes it. If a
+/// reorganization takes the locked block off our chain, the validator only
+/// votes above it, and precommitting a block with a prevote quorum there
+/// releases the lock. Finalizing a block finalizes its ancestors, and
+/// finalized blocks are never rolled back. Votes and locks are stored, so a
+/// restarted node neither votes twice nor forgets a lock.
+#[derive(Clone)]
+pub struct Finality {
+    blockchain: Blockchain,
+    pool: Arc<Mutex<VotePool>>,
+    votes: Tree, // "{height:020}:{kind}:{validator}" -> Vote, for heights above the finalized one
+    locks: Tree, // Own validator -> height and hash of its last precommit
+}
+
+impl Finality {
+    pub fn new(blockchain: Blockchain) -> Self {
+        let votes = blockchain.open_local_tree("votes");
+        let locks = blockchain.open_local_tree("vote_locks");
+        let mut pool = VotePool::default();
+        let finalized_height = blockchain.finalized_height();
+        for value in votes.iter().values() {
+            let vote: Vote = serde_json::from_slice(&value.expect("Failed to read vote from database")).expect("Corrupt vote in database");
+            if vote.height > finalized_height {
+                pool.record(vote);
+            }
+        }
+        for entry in locks.iter() {
+            let (validator, lock) = entry.expect("Failed to read vote lock from database");
+            let lock = serde_json::from_slice(&lock).expect("Corrupt vote lock in database");
+            pool.locks.insert(String::from_utf8(validator.to_vec()).unwrap(), lock);
+        }
+        Finality {
+            blockchain,
+            pool: Arc::new(Mutex::new(pool)),
+            votes,
+            locks,
+        }
+    }
+
+    /// Votes for `validator_keys` several times per slot and gossips the votes to peers.
+    pub fn start(&self, network: Arc<Network>, validator_keys: Vec<String>) {
+        let finality = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(BLOCK_TIME / 4).await;
+                for vote in finality.votes_to_cast(&validator_keys) {
+                    match finality.add_vote(vote.clone()) {
+                        Ok(()) => network.broadcast_vote(&vote),
+                        Err(e) => warn!("Discarding own {} for block {}: {}", vote.kind.as_str(), vote.height, e),
+                    }
+                }
+                finality.try_finalize();
+            }
+        });
+    }
+
+    /// Records a vote from this node or a peer.
+    pub fn add_vote(&self, vote: Vote) -> Result<(), ChainError> {
+        vote.verify_signature()?;
+        if vote.height <= self.blockchain.finalized_height() {
+            return Err(ChainError::AlreadyFinalized);
+        }
+        let eligible = self.blockchain
+            .get_validator(&vote.validator)
+            .is_some_and(|validator| validator.status == ValidatorStatus::Active);
+        if !eligible {
+            return Err(ChainError::InactiveValidator);
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+        let voted_key = (vote.kind, vote.height, vote.validator.clone());
+        match pool.voted.get(&voted_key) {
+            Some(hash) if *hash == vote.hash => return Ok(()), // Already counted
+            Some(_) => return Err(ChainError::ConflictingVote),
+            None => {}
+        }
+        debug!("{} from {} for block {} ({})", vote.kind.as_str(), vote.validator, vote.height, vote.hash);
+        self.votes.insert(vote_key(&vote), serde_json::to_vec(&vote).unwrap()).expect("Failed to store vote");
+        self.votes.flush().expect("Failed to flush votes");
+        pool.record(vote);
+        drop(pool);
+
+        self.try_finalize();
+        Ok(())
+    }
+
+    /// Prevotes for our tip, and precommits for any block on our chain that
+    /// has a prevote quorum, from each of `validator_keys` that has not voted
+    /// yet and whose last precommit is on our chain, or below the block voted
+    /// for. Precommitting locks the validator on the block.
+    fn votes_to_cast(&self, validator_keys: &[String]) -> Vec<Vote> {
+        let finalized_height = self.blockchain.finalized_height();
+        let mut targets = Vec::new();
+        if let Some(tip) = self.blockchain.get_last_block().filter(|tip| tip.index > finalized_height) {
+            targets.push((VoteKind::Prevote, tip.index, tip.hash));
+        }
+        {
+            let pool = self.pool.lock().unwrap();
+            for ((kind, height, hash), votes) in &pool.votes {
+                if *kind == VoteKind::Prevote && *height > finalized_height && self.is_on_chain(*height, hash) && self.has_quorum(votes) {
+                    targets.push((VoteKind::Precommit, *height, hash.clone()));
+                }
+            }
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+        let mut votes = Vec::new();
+        let mut locks_changed = false;
+        for (kind, height, hash) in targets {
+            for key in validator_keys {
+                let vote = match Vote::sign(kind, height, &hash, key) {
+                    Ok(vote) => vote,
+                    Err(e) => {
+                        warn!("Cannot sign {}: {}", kind.as_str(), e);
+                        continue;
+                    }
+                };
+                if pool.voted.contains_key(&(kind, height, vote.validator.clone())) {
+                    continue;
+                }
+                // Every block voted for is on our chain, so a vote is consistent with a lock on our chain
+                if let Some((locked_height, locked_hash)) = pool.locks.get(&vote.validator).cloned() {
+                    if !self.is_on_chain(locked_height, &locked_hash) {
+                        if height <= locked_height {
+                            debug!("{} is locked on block {} ({}), not voting for block {}", vote.validator, locked_height, locked_hash, height);
+                            continue;
+                        }
+                        if kind == VoteKind::Precommit {
+                            info!("{} unlocked from block {} by the prevote quorum for block {}", vote.validator, locked_height, height);
+                            pool.locks.remove(&vote.validator);
+                            self.locks.remove(vote.validator.as_bytes()).expect("Failed to remove vote lock");
+                            locks_changed = true;
+                        }
+                    }
+                }
+                if kind == VoteKind::Precommit && pool.locks.get(&vote.validator).is_none_or(|(locked_height, _)| height > *locked_height) {
+                    pool.locks.insert(vote.validator.clone(), (height, hash.clone()));
+                    self.locks.insert(vote.validator.as_bytes(), serde_json::to_vec(&(height, &hash)).unwrap()).expect("Failed to store vote lock");
+                    locks_changed = true;
+                }
+                votes.push(vote);
+            }
+        }
+        if locks_changed {
+            self.locks.flush().expect("Failed to flush vote locks"); // Before the votes go out
+        }
+        votes
+    }
+
+    /// Finalizes the highest block on our chain with a precommit quorum.
+    fn try_finalize(&self) {
+        let mut pool = self.pool.lock().unwrap();
+        let finalized_height = self.blockchain.finalized_height();
+        let certificate = pool.votes
+            .iter()
+            .filter(|((kind, height, hash), votes)| {
+                *kind == VoteKind::Precommit && *height > finalized_height && self.is_on_chain(*height, hash) && self.has_quorum(votes)
+            })
+            .max_by_key(|((_, height, _), _)| *height)
+            .map(|((_, height, hash), votes)| FinalityCertificate {
+                height: *height,
+                hash: hash.clone(),
+                precommits: votes.values().cloned().collect(),
+            });
+        let Some(certificate) = certificate else { return };
+
+        let (height, hash) = (certificate.height, certificate.hash.clone());
+        match self.blockchain.finalize(certificate) {
+            Ok(()) => {
+                pool.votes.retain(|(_, vote_height, _), _| *vote_height > height);
+                pool.voted.retain(|(_, vote_height, _), _| *vote_height > height);
+                pool.locks.retain(|_, (locked_height, _)| *locked_height > height);
+                self.prune(height, &pool);
+                info!("Block {} finalized ({})", height, hash);
+            }
+            Err(e) => warn!("Cannot finalize block {}: {}", height, e),
+        }
+    }
+
+    /// Deletes the stored votes up to the finalized `height`, and the locks no longer in `pool`.
+    fn prune(&self, height: u64, pool: &VotePool) {
+        for key in self.votes.range(..format!("{:020};", height)).keys() {
+            self.votes.remove(key.expect("Failed to read vote from database")).expect("Failed to remove vote");
+        }
+        for validator in self.locks.iter().keys() {
+            let validator = validator.expect("Failed to read vote lock from database");
+            if !pool.locks.contains_key(std::str::from_utf8(&validator).unwrap()) {
+                self.locks.remove(validator).expect("Failed to remove vote lock");
+            }
+        }
+    }
+
+    fn is_on_chain(&self, height: u64, hash: &str) -> bool {
+        self.blockchain.get_block(height).is_some_and(|block| block.hash == hash)
+    }
+
+    /// Whether `votes` come from validators holding more than 2/3 of the active stake.
+    fn has_quorum(&self, votes: &HashMap<String, Vote>) -> bool {
+        let validators = self.blockchain.get_validators();
+        let active = validators.iter().filter(|validator| validator.status == ValidatorStatus::Active);
+        let total_stake: u128 = active.clone().map(|validator| validator.total_stake() as u128).sum();
+        let voted_stake: u128 = active
+            .filter(|validator| votes.contains_key(&validator.address))
+            .map(|validator| validator.total_stake() as u128)
+            .sum();
+        total_stake > 0 && voted_stake * 3 > total_stake * 2
+    }
+}
+
+fn vote_key(vote: &Vote) -> String {
+    format!("{:020}:{}:{}", vote.height, vote.kind.as_str(), vote.validator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testing::*;
+    use crate::genesis::Genesis;
+    use crate::pos;
+
+    /// Development validators 0, 1 and 2, staking 100, 200 and 300 tokens.
+    fn keys() -> Vec<String> {
+        (0..pos::DEV_VALIDATORS).map(pos::dev_validator_key).collect()
+    }
+
+    /// Casts and records the votes due from `keys`.
+    fn vote(finality: &Finality, keys: &[String]) -> Vec<Vote> {
+        let votes = finality.votes_to_cast(keys);
+        for vote in &votes {
+            finality.add_vote(vote.clone()).unwrap();
+        }
+        votes
+    }
+
+    #[test]
+    fn two_thirds_of_the_stake_finalize_a_block() {
+        let chain = chain();
+        let block = produce(&chain, first_slot(), vec![]);
+        let finality = Finality::new(chain.clone());
+        let keys = keys();
+
+        let prevotes = vote(&finality, &keys[1..]);
+        assert!(prevotes.iter().all(|vote| vote.kind == VoteKind::Prevote && vote.hash == block.hash));
+        let precommits = vote(&finality, &keys[1..]);
+        assert!(precommits.iter().all(|vote| vote.kind == VoteKind::Precommit && vote.height == 1));
+        assert_eq!(chain.finalized_height(), 1);
+        assert_eq!(chain.get_finality_certificate(1).unwrap().hash, block.hash);
+    }
+
+    #[test]
+    fn half_of_the_stake_finalizes_nothing() {
+        let chain = chain();
+        produce(&chain, first_slot(), vec![]);
+        let finality = Finality::new(chain.clone());
+        let keys = keys();
+        assert_eq!(vote(&finality, &keys[..2]).len(), 2);
+        assert!(vote(&finality, &keys[..2]).is_empty());
+        assert_eq!(chain.finalized_height(), 0);
+    }
+
+    #[test]
+    fn votes_survive_a_restart() {
+        let chain = chain();
+        produce(&chain, first_slot(), vec![]);
+        let keys = keys();
+        vote(&Finality::new(chain.clone()), &keys[..1]);
+
+        let restarted = Finality::new(chain.clone());
+        assert!(restarted.votes_to_cast(&keys[..1]).is_empty());
+        let conflicting = Vote::sign(VoteKind::Prevote, 1, &"f".repeat(64), &keys[0]).unwrap();
+        assert_eq!(restarted.add_vote(conflicting), Err(ChainError::ConflictingVote));
+    }
+
+    #[test]
+    fn lock_on_a_block_that_left_the_chain_is_released_by_a_prevote_quorum_above_it() {
+        let (ours, theirs) = (chain(), chain());
+        let slot = first_slot();
+        let locked = produce(&ours, slot, vec![]);
+        produce(&theirs, slot, vec![transfer(&key("alice"), &address(&key("bob")), 1, 0)]);
+        let tip = produce(&theirs, slot + 1, vec![]);
+        let keys = keys();
+        let locked_validator = address(&keys[0]);
+
+        let finality = Finality::new(ours.clone());
+        vote(&finality, &keys);
+        assert_eq!(vote(&finality, &keys[..1]).len(), 1); // Precommit, locking validator 0 on block 1
+        let finality = Finality::new(ours.clone());
+        assert_eq!(finality.pool.lock().unwrap().locks.get(&locked_validator), Some(&(1, locked.hash.clone())));
+
+        ours.reorganize(theirs.get_blocks(1, 10)).unwrap();
+        let prevotes = vote(&finality, &keys);
+        assert!(prevotes.iter().all(|vote| vote.kind == VoteKind::Prevote && vote.hash == tip.hash));
+        assert_eq!(prevotes.len(), 3);
+        let precommits = vote(&finality, &keys[..1]);
+        assert_eq!((precommits[0].kind, precommits[0].height), (VoteKind::Precommit, 2));
+        assert_eq!(finality.pool.lock().unwrap().locks.get(&locked_validator), Some(&(2, tip.hash.clone())));
+    }
+
+    #[test]
+    fn quorum_does_not_overflow_with_large_stakes() {
+        let mut genesis = Genesis::default();
+        for validator in &mut genesis.validators {
+            validator.stake = u64::MAX / 4;
+        }
+        genesis.balances.clear();
+        let finality = Finality::new(Blockchain::temporary(&genesis));
+        let votes: HashMap<String, Vote> = keys()
+            .iter()
+            .map(|key| (address(key), Vote::sign(VoteKind::Prevote, 1, "hash", key).unwrap()))
+            .collect();
+        assert!(finality.has_quorum(&votes));
+        let two_thirds: HashMap<String, Vote> = votes.into_iter().skip(1).collect();
+        assert!(!finality.has_quorum(&two_thirds));
+    }
+}
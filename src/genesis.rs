@@ -0,0 +1,146 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use hex::encode;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::blockchain::Block;
+use crate::consensus::ConsensusKind;
+use crate::monetary::MonetaryPolicy;
+use crate::pos;
+
+/// The genesis file the development validators start from, used when no other is given.
+const DEV_GENESIS: &str = include_str!("../genesis.json");
+
+/// Initial state and rules of a chain. Every node of a chain must start from
+/// the same genesis; its hash is the hash of block 0.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Genesis {
+    pub chain_id: String,
+    pub timestamp: u64,                  // Timestamp of block 0
+    pub balances: BTreeMap<String, u64>, // Address -> spendable balance
+    pub validators: Vec<GenesisValidator>,
+    pub consensus: ConsensusParams,
+    #[serde(default)]
+    pub monetary_policy: MonetaryPolicy,
+}
+
+/// A validator and the stake it starts with, bonded on top of its balance.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisValidator {
+    pub address: String,
+    pub stake: u64,
+}
+
+/// Rules every node of a chain must agree on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConsensusParams {
+    pub engine: ConsensusKind,
+    pub max_block_transactions: usize,
+    pub max_block_bytes: usize, // Serialized size of the included transactions
+    pub max_missed_slots: u64,  // Consecutive missed slots before a validator is slashed for downtime
+}
+
+impl Default for Genesis {
+    fn default() -> Self {
+        serde_json::from_str(DEV_GENESIS).expect("Bundled genesis.json is invalid")
+    }
+}
+
+impl Genesis {
+    /// Reads and checks a genesis file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let genesis: Genesis = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+        genesis.validate()?;
+        Ok(genesis)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() {
+            return Err("chain_id is empty".to_string());
+        }
+        let can_propose = self.validators.iter().any(|validator| validator.stake >= pos::MIN_VALIDATOR_STAKE);
+        if self.consensus.engine == ConsensusKind::ProofOfStake && !can_propose {
+            return Err(format!("Proof-of-stake needs a validator with a stake of at least {}", pos::MIN_VALIDATOR_STAKE));
+        }
+        let mut addresses = HashSet::new();
+        for validator in &self.validators {
+            if validator.stake == 0 {
+                return Err(format!("Validator {} has no stake", validator.address));
+            }
+            if !addresses.insert(&validator.address) {
+                return Err(format!("Validator {} is listed twice", validator.address));
+            }
+        }
+        let stakes = self.validators.iter().map(|validator| &validator.stake);
+        if self.balances.values().chain(stakes).try_fold(0u64, |total, amount| total.checked_add(*amount)).is_none() {
+            return Err("Balances and stakes add up to more than a u64 can hold".to_string());
+        }
+        self.monetary_policy.validate()
+    }
+
+    /// Block 0. It has no transactions, so its `merkle_root` commits to the
+    /// whole genesis instead, and its hash identifies the chain.
+    pub fn block(&self) -> Block {
+        let mut block = Block {
+            index: 0,
+            previous_hash: "0".to_string(),
+            timestamp: self.timestamp,
+            merkle_root: encode(Sha256::digest(serde_json::to_vec(self).unwrap())),
+            transactions: Vec::new(),
+            proof: 0,
+            difficulty: 0,
+            reward: 0,
+            hash: String::new(),
+            validator: String::new(),
+            public_key: String::new(),
+            signature: String::new(),
+        };
+        block.hash = block.header().calculate_hash();
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(change: impl FnOnce(&mut Genesis)) -> String {
+        let mut genesis = Genesis::default();
+        change(&mut genesis);
+        genesis.validate().unwrap_err()
+    }
+
+    #[test]
+    fn bundled_genesis_is_valid() {
+        Genesis::default().validate().unwrap();
+    }
+
+    #[test]
+    fn proof_of_stake_needs_a_validator_that_can_propose() {
+        assert!(rejected(|genesis| genesis.validators.clear()).contains("at least 100"));
+        let below_minimum = |genesis: &mut Genesis| {
+            for validator in &mut genesis.validators {
+                validator.stake = pos::MIN_VALIDATOR_STAKE - 1;
+            }
+        };
+        assert!(rejected(below_minimum).contains("at least 100"));
+
+        let mut genesis = Genesis::default();
+        below_minimum(&mut genesis);
+        genesis.validators[1].stake = pos::MIN_VALIDATOR_STAKE;
+        genesis.validate().unwrap();
+        genesis.validators.clear();
+        genesis.consensus.engine = ConsensusKind::ProofOfWork;
+        genesis.validate().unwrap();
+    }
+
+    #[test]
+    fn invalid_genesis_is_rejected() {
+        assert_eq!(rejected(|genesis| genesis.chain_id.clear()), "chain_id is empty");
+        assert!(rejected(|genesis| genesis.validators[0].stake = 0).contains("has no stake"));
+        assert!(rejected(|genesis| genesis.validators.push(genesis.validators[0].clone())).contains("listed twice"));
+        assert!(rejected(|genesis| genesis.validators[0].stake = u64::MAX).contains("more than a u64"));
+        assert!(rejected(|genesis| genesis.monetary_policy.reduction_percent = 101).contains("reduction_percent"));
+    }
+}
@@ -0,0 +1,17 @@
+pub mod api;
+pub mod blockchain;
+pub mod consensus;
+pub mod crypto;
+pub mod error;
+pub mod faucet;
+pub mod finality;
+pub mod genesis;
+pub mod mempool;
+pub mod merkle;
+pub mod monetary;
+pub mod network;
+pub mod pos;
+pub mod pow;
+pub mod rpc;
+pub mod state;
+pub mod subscriptions;
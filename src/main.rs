@@ -1,275 +1,211 @@
-use std::collections::{HashMap, HashSet};
-use rand::{seq::IteratorRandom, Rng};
-use sha2::{Sha256, Digest};
-use hex::encode;
 use serde::{Serialize, Deserialize};
 use warp::Filter;
-use tokio;
+use warp::http::StatusCode;
+use std::convert::Infallible;
+use std::sync::Arc;
 use chrono::Utc;
-use std::sync::{Arc, Mutex};
-use log::{info, error, debug};
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct KeyPair {
-    pub private_key: String,
-    pub public_key: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Transaction {
-    pub id: String,
-    pub sender: String,
-    pub recipient: String,
-    pub amount: u64,
+use clap::{Parser, Subcommand};
+use log::{debug, info, warn};
+
+use pos_blockchain::api::{BalanceResponse, BlockDetails, SubmitResponse, SupplyResponse, TransactionDetails};
+use pos_blockchain::blockchain::{Block, Blockchain, DoubleSignEvidence, Transaction, TransactionKind};
+use pos_blockchain::crypto;
+use pos_blockchain::error::ChainError;
+use pos_blockchain::faucet::{self, Faucet, FaucetConfig, ADMIN_HEADER};
+use pos_blockchain::finality::{Finality, Vote};
+use pos_blockchain::genesis::Genesis;
+use pos_blockchain::mempool::{MempoolConfig, DEFAULT_MIN_FEE};
+use pos_blockchain::network::{ChainStatus, Network, GENESIS_HEADER, SYNC_BATCH};
+use pos_blockchain::pos;
+use pos_blockchain::rpc::Rpc;
+use pos_blockchain::subscriptions;
+
+const DB_PATH: &str = "mohsin_chain_db";
+/// Entries per page of `/blocks` and `/address/{addr}/transactions` when `limit` is omitted.
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Parser)]
+#[command(name = "node", about = "Runs a MOHSIN CHAIN node")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Port for the HTTP API and peer-to-peer traffic
+    #[arg(long, default_value_t = 3030)]
+    port: u16,
+
+    /// Comma-separated peer addresses, e.g. 127.0.0.1:3031,127.0.0.1:3032
+    #[arg(long, value_delimiter = ',')]
+    peers: Vec<String>,
+
+    /// Database directory [default: mohsin_chain_db/<port>]
+    #[arg(long)]
+    db: Option<String>,
+
+    /// Development validators this node produces blocks for, e.g. 0,2, when no validator key is given [default: all of them]
+    #[arg(long, value_delimiter = ',')]
+    validators: Vec<u32>,
+
+    /// Comma-separated private keys of the validators this node produces blocks and votes for. Visible to other local users in the process list; prefer --validator-key-file
+    #[arg(long, value_delimiter = ',', conflicts_with = "validators")]
+    validator_key: Vec<String>,
+
+    /// File with one validator private key per line, added to --validator-key. Blank lines and lines starting with # are skipped
+    #[arg(long, conflicts_with = "validators")]
+    validator_key_file: Option<String>,
+
+    /// Lowest transaction fee this node accepts into its mempool
+    #[arg(long, default_value_t = DEFAULT_MIN_FEE)]
+    min_fee: u64,
+
+    /// Most transactions the mempool holds; when full, the lowest fee per byte is evicted
+    #[arg(long, default_value_t = MempoolConfig::default().max_size)]
+    mempool_size: usize,
+
+    /// Most pending transactions one sender may have in the mempool
+    #[arg(long, default_value_t = MempoolConfig::default().max_per_sender)]
+    mempool_per_sender: usize,
+
+    /// Seconds a transaction may wait in the mempool before it is dropped
+    #[arg(long, default_value_t = MempoolConfig::default().ttl)]
+    mempool_ttl: u64,
+
+    /// Genesis file with the chain id, initial balances and validators, and consensus parameters. All nodes of a chain must use the same one [default: the bundled genesis.json]
+    #[arg(long)]
+    genesis: Option<String>,
+
+    /// Private key of the funded account /airdrop_tokens sends from. Without it the node runs no faucet, unless --dev is given
+    #[arg(long)]
+    faucet_key: Option<String>,
+
+    /// Most tokens one /airdrop_tokens request may ask the faucet for
+    #[arg(long, default_value_t = 1000)]
+    faucet_max_amount: u64,
+
+    /// Seconds before the faucet sends to the same address again
+    #[arg(long, default_value_t = 3600)]
+    faucet_cooldown: u64,
+
+    /// Total tokens the faucet may send from this node
+    #[arg(long, default_value_t = 100_000)]
+    faucet_budget: u64,
+
+    /// Secret that lets /airdrop_tokens requests with an x-admin-key header mint tokens without limits
+    #[arg(long, requires = "mint_key")]
+    admin_key: Option<String>,
+
+    /// Private key of the genesis mint authority, which signs the mint transactions of admin airdrops
+    #[arg(long)]
+    mint_key: Option<String>,
+
+    /// Answer /balance, /transaction, /transfer and /airdrop_tokens with the old English sentences instead of JSON objects
+    #[arg(long)]
+    legacy_responses: bool,
+
+    /// Development mode: run the faucet on the development faucet account of the bundled genesis.json when no --faucet-key is given, and sign transactions from private keys sent to /sign. The faucet's private key is public, so anyone can spend its tokens
+    #[arg(long)]
+    dev: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Block {
-    pub index: u64,
-    pub previous_hash: String,
-    pub timestamp: u64,
-    pub transaction: Option<Transaction>,
-    pub proof: u64,
-    pub hash: String,
-    pub validator: String, // New field to store the validator
+#[derive(Subcommand)]
+enum Command {
+    /// Signs a transaction without a node and prints it as JSON, ready for /transaction
+    Sign(SignArgs),
 }
 
-#[derive(Clone)]
-pub struct Blockchain {
-    db: Arc<Mutex<HashMap<u64, Block>>>,
-    token_balances: Arc<Mutex<HashMap<String, u64>>>,
-    transactions: Arc<Mutex<HashMap<String, Transaction>>>,
-    current_block: Arc<Mutex<Option<Block>>>,
-    block_index: Arc<Mutex<u64>>,
-    mempool: Arc<Mutex<Vec<Transaction>>>,
-    airdropped_addresses: Arc<Mutex<HashSet<String>>>,
-    validators: Arc<Mutex<HashSet<String>>>, // Set of validators
-}
-
-impl Blockchain {
-    pub fn new() -> Self {
-        let mut blockchain = Blockchain {
-            db: Arc::new(Mutex::new(HashMap::new())),
-            token_balances: Arc::new(Mutex::new(HashMap::new())),
-            transactions: Arc::new(Mutex::new(HashMap::new())),
-            current_block: Arc::new(Mutex::new(None)),
-            block_index: Arc::new(Mutex::new(1)),
-            mempool: Arc::new(Mutex::new(Vec::new())),
-            airdropped_addresses: Arc::new(Mutex::new(HashSet::new())),
-            validators: Arc::new(Mutex::new(HashSet::new())),
-        };
-
-        // Create and airdrop tokens to a random address at startup
-        blockchain.airdrop_tokens(1000); // Airdrop 1000 tokens
-
-        // Add 5 validators
-        for _ in 0..5 {
-            blockchain.add_validator(Self::generate_random_address());
-        }
-
-        blockchain
-    }
-
-    pub fn get_transaction(&self, id: &str) -> Option<Transaction> {
-        let txs = self.transactions.lock().unwrap();
-        txs.get(id).cloned()
-    }
-
-    pub fn get_last_block(&self) -> Option<Block> {
-        let last_index = {
-            let index = self.block_index.lock().unwrap();
-            *index - 1
-        };
-        let db = self.db.lock().unwrap();
-        db.get(&last_index).cloned()
-    }
-
-    pub fn add_block(&self, block: Block) {
-        let mut db = self.db.lock().unwrap();
-        db.insert(block.index, block.clone());
-
-        // Update the current block to None after adding it
-        let mut current_block = self.current_block.lock().unwrap();
-        *current_block = None;
+#[derive(clap::Args)]
+struct SignArgs {
+    /// File with the sender's private key [default: read from standard input]
+    #[arg(long)]
+    key_file: Option<String>,
 
-        info!("Block added with index: {}, hash: {}, validator: {}", block.index, block.hash, block.validator);
+    /// transfer, stake, unstake, delegate, evidence or mint
+    #[arg(long, default_value = "transfer", value_parser = parse_kind)]
+    kind: TransactionKind,
 
-        // Reward the validator
-        self.update_balance(&block.validator, 1).unwrap();
-    }
-
-    pub fn get_block(&self, index: u64) -> Option<Block> {
-        let db = self.db.lock().unwrap();
-        db.get(&index).cloned()
-    }
-
-    pub fn update_balance(&self, address: &str, amount: i64) -> Result<(), &'static str> {
-        let mut balances = self.token_balances.lock().unwrap();
-        let balance = balances.entry(address.to_string()).or_insert(0);
-
-        // Check if balance is sufficient for withdrawal
-        if *balance as i64 + amount < 0 {
-            return Err("Insufficient funds");
-        }
-
-        *balance = (*balance as i64 + amount) as u64; // Ensure no negative balances
-        Ok(())
-    }
-
-    pub fn get_balance(&self, address: &str) -> u64 {
-        let balances = self.token_balances.lock().unwrap();
-        *balances.get(address).unwrap_or(&0)
-    }
+    /// Address that receives the tokens, or the validator for stake, unstake, delegate and evidence
+    #[arg(long)]
+    recipient: String,
 
-    pub fn add_transaction(&self, transaction: Transaction) {
-        {
-            let mut txs = self.transactions.lock().unwrap();
-            txs.insert(transaction.id.clone(), transaction.clone());
-            debug!("Transaction added: {:?}", transaction); // Log added transaction
-        }
-    
-        {
-            let mut mempool = self.mempool.lock().unwrap();
-            mempool.push(transaction);
-        }
-    }
-
-    pub fn start_mining(&self) {
-        let blockchain = self.clone();
-        tokio::spawn(async move {
-            loop {
-                let block = blockchain.mine_block().await;
-                blockchain.add_block(block);
-                tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-            }
-        });
-    }
-
-    async fn mine_block(&self) -> Block {
-        let block_index = {
-            let mut index = self.block_index.lock().unwrap();
-            let current_index = *index;
-            *index += 1;
-            current_index
-        };
-
-        let previous_hash = match *self.current_block.lock().unwrap() {
-            Some(ref block) => block.hash.clone(),
-            None => "0".to_string(),
-        };
-
-        let mut mempool = self.mempool.lock().unwrap();
-        let transaction_to_include = mempool.pop(); // Take one transaction
-
-        let proof = Self::proof_of_work(&previous_hash);
-        let hash = Self::calculate_hash(block_index, &previous_hash, &[transaction_to_include.clone()], proof);
-
-        // Select a validator (randomly for simplicity)
-        let validator = {
-            let validators = self.validators.lock().unwrap();
-            validators.iter().cloned().choose(&mut rand::thread_rng()).unwrap_or_else(|| "None".to_string())
-        };
-
-        let block = Block {
-            index: block_index,
-            previous_hash: previous_hash.clone(),
-            timestamp: Utc::now().timestamp() as u64,
-            transaction: transaction_to_include.clone(),
-            proof,
-            hash,
-            validator,
-        };
-
-        // Set the new block as the current block
-        let mut current_block = self.current_block.lock().unwrap();
-        *current_block = Some(block.clone());
-
-        // If no transaction was included, airdrop tokens to a random validator
-        if transaction_to_include.is_none() {
-            self.airdrop_tokens_to_random_validator(2); // Airdrop 100 tokens
-        }
-
-        block
-    }
-
-    fn proof_of_work(previous_hash: &str) -> u64 {
-        let mut proof = 0;
-        let target = "0000";
-        while !Self::calculate_hash(0, previous_hash, &[], proof).starts_with(target) {
-            proof += 1;
-        }
-        proof
-    }
-
-    fn calculate_hash(index: u64, previous_hash: &str, transaction: &[Option<Transaction>], proof: u64) -> String {
-        let transaction_str = transaction.iter().map(|t| format!("{:?}", t)).collect::<String>();
-        let input = format!("{}{}{}{}{}", index, previous_hash, transaction_str, proof, "MOHSIN");
-        let mut hasher = Sha256::new();
-        hasher.update(input);
-        let result = hasher.finalize();
-        encode(result)
-    }
-
-    pub fn add_validator(&self, address: String) {
-        let mut validators = self.validators.lock().unwrap();
-        validators.insert(address.clone());
-        info!("Validator added: {}", address);
-    }
+    /// Tokens to send, stake, unstake, delegate or mint
+    #[arg(long, default_value_t = 0)]
+    amount: u64,
 
-    pub fn remove_validator(&self, address: &str) {
-        let mut validators = self.validators.lock().unwrap();
-        if validators.remove(address) {
-            info!("Validator removed: {}", address);
-        } else {
-            error!("Validator {} not found", address);
-        }
-    }
+    /// Fee paid to the proposer; at least the receiving node's --min-fee
+    #[arg(long, default_value_t = DEFAULT_MIN_FEE)]
+    fee: u64,
 
-    pub fn airdrop_tokens(&self, amount: u64) {
-        let address = Self::generate_random_address();
-        let mut airdropped_addresses = self.airdropped_addresses.lock().unwrap();
-        if !airdropped_addresses.contains(&address) {
-            self.update_balance(&address, amount as i64).unwrap();
-            airdropped_addresses.insert(address.clone());
-            info!("Airdropped {} MOHSIN tokens to address {}", amount, address);
-        } else {
-            error!("Address {} already airdropped", address);
-        }
-    }
+    /// The sender's next expected nonce, see /nonce/{address}
+    #[arg(long)]
+    nonce: u64,
 
-    fn airdrop_tokens_to_random_validator(&self, amount: u64) {
-        let validator = {
-            let validators = self.validators.lock().unwrap();
-            validators.iter().cloned().choose(&mut rand::thread_rng()).unwrap_or_else(|| "None".to_string())
-        };
-        
-        if validator != "None" {
-            self.update_balance(&validator, amount as i64).unwrap();
-            info!("Airdropped {} MOHSIN tokens to validator {}", amount, validator);
-        } else {
-            error!("No validators available for airdrop");
-        }
-    }
-
-    fn generate_random_address() -> String {
-        let mut rng = rand::thread_rng();
-        (0..64).map(|_| rng.sample(rand::distributions::Alphanumeric) as char).collect()
-    }
+    /// File with the two conflicting blocks of an evidence transaction, as {"first": {...}, "second": {...}}
+    #[arg(long)]
+    evidence_file: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    let args = Args::parse();
+    if let Some(Command::Sign(sign)) = args.command {
+        return sign_offline(sign);
+    }
 
-    let blockchain = Arc::new(Blockchain::new());
-    blockchain.start_mining(); // Start mining in a background task
-
+    let db_path = args.db.unwrap_or_else(|| format!("{}/{}", DB_PATH, args.port));
+    let mempool = MempoolConfig {
+        max_size: args.mempool_size,
+        max_per_sender: args.mempool_per_sender,
+        ttl: args.mempool_ttl,
+        min_fee: args.min_fee,
+        ..MempoolConfig::default()
+    };
+    let genesis = match &args.genesis {
+        Some(path) => Genesis::load(path).unwrap_or_else(|e| panic!("Invalid genesis file {}: {}", path, e)),
+        None => Genesis::default(),
+    };
+    if let Err(e) = args.mint_key.as_deref().map_or(Ok(()), |key| genesis.monetary_policy.check_mint_key(key)) {
+        fail(format!("Invalid --mint-key: {}", e));
+    }
+    let blockchain = Arc::new(Blockchain::new(&db_path, &genesis, mempool));
+    let network = Arc::new(Network::new(args.peers, blockchain.genesis_hash()));
+    let faucet_key = args.faucet_key.or_else(|| args.dev.then(faucet::dev_faucet_key));
+    let faucet = faucet_key.map(|private_key| {
+        let faucet_config = FaucetConfig {
+            private_key,
+            max_amount: args.faucet_max_amount,
+            cooldown: args.faucet_cooldown,
+            budget: args.faucet_budget,
+        };
+        Arc::new(Faucet::new(faucet_config, &blockchain).expect("Invalid faucet key"))
+    });
+    if faucet.is_none() {
+        info!("No faucet: start with --faucet-key, or --dev for the development faucet");
+    }
+    let validator_keys = load_validator_keys(args.validator_key, args.validator_key_file.as_deref(), args.validators);
+    let reporter_key = validator_keys.first().cloned(); // Signs evidence of double-signing seen by this node
+    let finality = Arc::new(Finality::new(blockchain.as_ref().clone()));
+    finality.start(network.clone(), validator_keys.clone()); // Vote on blocks in the background
+    blockchain.start_mining(network.clone(), validator_keys); // Start mining in a background task
+    network.clone().start_sync(blockchain.as_ref().clone()); // Catch up with peers in the background
+
+    let peer_filter = same_genesis(blockchain.genesis_hash().to_string());
     let blockchain_filter = warp::any().map(move || blockchain.clone());
+    let network_peers = network.peers().to_vec();
+    let network_filter = warp::any().map(move || network.clone());
+    let finality_filter = warp::any().map(move || finality.clone());
+    let legacy_responses = args.legacy_responses;
+    let legacy_filter = warp::any().map(move || legacy_responses);
+    let faucet_filter = warp::any().map(move || faucet.clone());
+    let admin = args.admin_key.zip(args.mint_key); // (admin key, mint key); the admin key requires a mint key
+    let admin_filter = warp::any().map(move || admin.clone());
+    let dev = args.dev;
 
     let new_address = warp::path("new_address")
         .and(warp::get())
         .map(|| {
-            let key_pair = generate_key_pair();
+            let key_pair = crypto::generate_key_pair();
             warp::reply::json(&key_pair)
         });
 
@@ -277,46 +213,58 @@ async fn main() {
         .and(warp::get())
         .and(warp::path::param::<String>())
         .and(blockchain_filter.clone())
-        .map(|address: String, blockchain: Arc<Blockchain>| {
-            let balance = blockchain.get_balance(&address);
-            warp::reply::json(&format!("Balance for address {}: {} MOHSIN tokens", address, balance))
+        .and(legacy_filter)
+        .map(|address: String, blockchain: Arc<Blockchain>, legacy: bool| {
+            let response = BalanceResponse::new(address, &blockchain);
+            respond(legacy, &response, || format!("Balance for address {}: {} MOHSIN tokens", response.address, response.balance))
         });
 
     let transaction = warp::path("transaction")
         .and(warp::post())
         .and(warp::body::json())
         .and(blockchain_filter.clone())
-        .map(|transaction: Transaction, blockchain: Arc<Blockchain>| {
-            blockchain.add_transaction(transaction.clone());
-            warp::reply::json(&format!("Transaction added with ID: {}", transaction.id))
+        .and(network_filter.clone())
+        .and(legacy_filter)
+        .and_then(|mut transaction: Transaction, blockchain: Arc<Blockchain>, network: Arc<Network>, legacy: bool| async move {
+            transaction.fill_id();
+            blockchain.add_transaction(transaction.clone()).map_err(warp::reject::custom)?;
+            network.broadcast_transaction(&transaction);
+            let response = SubmitResponse::pending(&transaction);
+            Ok::<_, warp::Rejection>(respond(legacy, &response, || format!("Transaction added with ID: {}", response.tx_id)))
         });
 
     let transfer_tokens = warp::path("transfer")
         .and(warp::post())
         .and(warp::body::json())
         .and(blockchain_filter.clone())
-        .map(|transfer: TransferRequest, blockchain: Arc<Blockchain>| {
-            let TransferRequest { from, to, amount } = transfer;
-            if blockchain.update_balance(&from, -(amount as i64 + 1)).is_err() { // Deduct fee here
-                return warp::reply::json(&"Insufficient funds".to_string());
-            }
-            blockchain.update_balance(&to, amount as i64).unwrap();
-            let transaction = Transaction {
-                id: generate_transaction_id(),
+        .and(network_filter.clone())
+        .and(legacy_filter)
+        .and_then(|transfer: TransferRequest, blockchain: Arc<Blockchain>, network: Arc<Network>, legacy: bool| async move {
+            let TransferRequest { from, to, amount, fee, nonce, public_key, signature } = transfer;
+            let fee = fee.unwrap_or_else(|| blockchain.min_fee());
+            let mut transaction = Transaction {
+                id: String::new(),
+                kind: TransactionKind::Transfer,
                 sender: from.clone(),
                 recipient: to.clone(),
                 amount,
+                fee,
+                nonce,
+                public_key,
+                signature,
+                evidence: None,
             };
-            blockchain.add_transaction(transaction.clone());
-            // Reward a randomly selected validator with the fee
-            let validator = {
-                let validators = blockchain.validators.lock().unwrap();
-                validators.iter().cloned().choose(&mut rand::thread_rng()).unwrap_or_else(|| "None".to_string())
-            };
-            if validator != "None" {
-                blockchain.update_balance(&validator, 1).unwrap();
+            transaction.fill_id();
+            // Balances only change when the transaction is included in a block
+            let available = blockchain.get_balance(&from);
+            let required = amount.checked_add(fee).ok_or_else(|| warp::reject::custom(ChainError::InvalidAmount("Amount plus fee overflows")))?;
+            if available < required {
+                return Err(warp::reject::custom(ChainError::InsufficientFunds { available, required }));
             }
-            warp::reply::json(&format!("Transferred {} MOHSIN tokens from {} to {}. Transaction ID: {}", amount, from, to, transaction.id))
+            blockchain.add_transaction(transaction.clone()).map_err(warp::reject::custom)?;
+            network.broadcast_transaction(&transaction);
+            let response = SubmitResponse::pending(&transaction);
+            Ok(respond(legacy, &response, || format!("Transfer of {} MOHSIN tokens from {} to {} submitted. Transaction ID: {}", amount, from, to, response.tx_id)))
         });
 
 
@@ -326,27 +274,265 @@ async fn main() {
         .and(blockchain_filter.clone())
         .and_then(get_transaction);
 
+    let block_details = warp::path("block")
+        .and(warp::get())
+        .and(warp::path::param::<u64>())
+        .and(blockchain_filter.clone())
+        .and_then(|height: u64, blockchain: Arc<Blockchain>| async move {
+            let block = blockchain.get_block(height).ok_or_else(|| warp::reject::custom(ChainError::BlockNotFound(height)))?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&BlockDetails::new(block, &blockchain)))
+        });
+
+    // Block explorer
+    let latest_block = warp::path!("blocks" / "latest")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|blockchain: Arc<Blockchain>| async move {
+            let block = blockchain
+                .get_last_block()
+                .ok_or_else(|| warp::reject::custom(ChainError::BlockNotFound(blockchain.next_block_index() - 1)))?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&BlockDetails::new(block, &blockchain)))
+        });
+
+    let block_by_height = warp::path!("blocks" / u64)
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|height: u64, blockchain: Arc<Blockchain>| async move {
+            let block = blockchain.get_block(height).ok_or_else(|| warp::reject::custom(ChainError::BlockNotFound(height)))?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&BlockDetails::new(block, &blockchain)))
+        });
+
+    let block_by_hash = warp::path!("blocks" / "hash" / String)
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|hash: String, blockchain: Arc<Blockchain>| async move {
+            let block = blockchain.get_block_by_hash(&hash).ok_or_else(|| warp::reject::custom(ChainError::BlockHashNotFound(hash)))?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&BlockDetails::new(block, &blockchain)))
+        });
+
+    let blocks = warp::path!("blocks")
+        .and(warp::get())
+        .and(warp::query::<PageQuery>())
+        .and(blockchain_filter.clone())
+        .map(|query: PageQuery, blockchain: Arc<Blockchain>| {
+            let blocks: Vec<BlockDetails> = blockchain
+                .get_blocks(query.from.unwrap_or(1), query.limit())
+                .into_iter()
+                .map(|block| BlockDetails::new(block, &blockchain))
+                .collect();
+            warp::reply::json(&blocks)
+        });
+
+    let address_transactions = warp::path!("address" / String / "transactions")
+        .and(warp::get())
+        .and(warp::query::<PageQuery>())
+        .and(blockchain_filter.clone())
+        .map(|address: String, query: PageQuery, blockchain: Arc<Blockchain>| {
+            let transactions: Vec<TransactionDetails> = blockchain
+                .get_address_transactions(&address, query.from.unwrap_or(0), query.limit())
+                .into_iter()
+                .map(|included| {
+                    let finalized = blockchain.is_finalized(included.block_index);
+                    TransactionDetails::included(included, finalized)
+                })
+                .collect();
+            warp::reply::json(&transactions)
+        });
+
+    // Sends from the faucet, or mints in a block without limits for requests with the admin key
     let airdrop_tokens = warp::path("airdrop_tokens")
         .and(warp::post())
         .and(warp::body::json())
+        .and(warp::header::optional::<String>(ADMIN_HEADER))
         .and(blockchain_filter.clone())
-        .map(|airdrop_request: AirdropRequest, blockchain: Arc<Blockchain>| {
+        .and(network_filter.clone())
+        .and(faucet_filter.clone())
+        .and(admin_filter)
+        .and(legacy_filter)
+        .and_then(|airdrop_request: AirdropRequest, given_key: Option<String>, blockchain: Arc<Blockchain>, network: Arc<Network>, faucet: Option<Arc<Faucet>>, admin: Option<(String, String)>, legacy: bool| async move {
+            let transaction = match given_key {
+                Some(given_key) => {
+                    let Some((_, mint_key)) = admin.filter(|(admin_key, _)| faucet::is_admin_key(admin_key, &given_key)) else {
+                        return Err(warp::reject::custom(ChainError::Unauthorized));
+                    };
+                    mint(&airdrop_request, &mint_key, &blockchain)
+                }
+                None => match &faucet {
+                    Some(faucet) => send_from_faucet(&airdrop_request, faucet, &blockchain),
+                    None => Err(ChainError::FaucetDisabled),
+                },
+            }
+            .map_err(warp::reject::custom)?;
+            network.broadcast_transaction(&transaction);
             let AirdropRequest { address, amount } = airdrop_request;
-            match blockchain.update_balance(&address, amount as i64) {
-                Ok(_) => warp::reply::json(&format!("Airdropped {} MOHSIN tokens to address {}", amount, address)),
-                Err(e) => warp::reply::json(&e.to_string()),
+            let response = AirdropResponse { balance: blockchain.get_balance(&address), address, amount, tx_id: transaction.id };
+            Ok::<_, warp::Rejection>(respond(legacy, &response, || format!("Airdropped {} MOHSIN tokens to address {}", response.amount, response.address)))
+        });
+
+    let faucet_status = warp::path("faucet")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and(faucet_filter)
+        .and_then(|blockchain: Arc<Blockchain>, faucet: Option<Arc<Faucet>>| async move {
+            let faucet = faucet.ok_or_else(|| warp::reject::custom(ChainError::FaucetDisabled))?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&faucet.status(&blockchain)))
+        });
+
+    let rpc = warp::path!("rpc")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and(blockchain_filter.clone())
+        .and(network_filter.clone())
+        .map(|body: warp::hyper::body::Bytes, blockchain: Arc<Blockchain>, network: Arc<Network>| -> Box<dyn warp::Reply> {
+            let rpc = Rpc { blockchain: &blockchain, network: &network };
+            match rpc.handle(&body) {
+                Some(response) => Box::new(warp::reply::json(&response)),
+                None => Box::new(StatusCode::NO_CONTENT), // Only notifications
             }
         });
-    
+
+    let ws = warp::path!("ws")
+        .and(warp::ws())
+        .and(blockchain_filter.clone())
+        .map(|ws: warp::ws::Ws, blockchain: Arc<Blockchain>| ws.on_upgrade(move |socket| subscriptions::serve(socket, blockchain)));
+
+    // Gossip from peers. Nothing received here is forwarded again.
+    let p2p_transaction = warp::path!("p2p" / "transaction")
+        .and(warp::post())
+        .and(peer_filter.clone())
+        .and(warp::body::json())
+        .and(blockchain_filter.clone())
+        .map(|transaction: Transaction, blockchain: Arc<Blockchain>| {
+            if let Err(e) = blockchain.add_transaction(transaction.clone()) {
+                warn!("Rejected transaction {} from peer: {}", transaction.id, e);
+            }
+            warp::reply()
+        });
+
+    let p2p_block = warp::path!("p2p" / "block")
+        .and(warp::post())
+        .and(peer_filter.clone())
+        .and(warp::body::json())
+        .and(blockchain_filter.clone())
+        .and(network_filter.clone())
+        .and(warp::any().map(move || reporter_key.clone()))
+        .and_then(|block: Block, blockchain: Arc<Blockchain>, network: Arc<Network>, reporter_key: Option<String>| async move {
+            match blockchain.add_block(block.clone()) {
+                Ok(()) => Ok(warp::reply::json(&format!("Block {} added", block.index))),
+                Err(e) => {
+                    if blockchain.get_block(block.index).is_some_and(|ours| ours.hash == block.hash) {
+                        debug!("Already have block {} from peer", block.index);
+                    } else {
+                        warn!("Rejected block {} from peer: {}", block.index, e);
+                    }
+                    if let (Some(evidence), Some(key)) = (blockchain.find_double_sign(&block), &reporter_key) {
+                        report_double_sign(evidence, key, &blockchain, &network);
+                    }
+                    Err(warp::reject::custom(ChainError::from(e)))
+                }
+            }
+        });
+
+    let p2p_vote = warp::path!("p2p" / "vote")
+        .and(warp::post())
+        .and(peer_filter.clone())
+        .and(warp::body::json())
+        .and(finality_filter.clone())
+        .map(|vote: Vote, finality: Arc<Finality>| {
+            if let Err(e) = finality.add_vote(vote.clone()) {
+                debug!("Ignored {} for block {} from {}: {}", vote.kind.as_str(), vote.height, vote.validator, e);
+            }
+            warp::reply()
+        });
+
+    let p2p_status = warp::path!("p2p" / "status")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .map(|blockchain: Arc<Blockchain>| warp::reply::json(&ChainStatus::of(&blockchain)));
+
+    let p2p_blocks = warp::path!("p2p" / "blocks")
+        .and(warp::get())
+        .and(peer_filter.clone())
+        .and(warp::query::<BlockRangeQuery>())
+        .and(blockchain_filter.clone())
+        .map(|query: BlockRangeQuery, blockchain: Arc<Blockchain>| {
+            warp::reply::json(&blockchain.get_blocks(query.from, query.limit.min(SYNC_BATCH)))
+        });
+
+    let sign_transaction = warp::path("sign")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(blockchain_filter.clone())
+        .and_then(move |request: SignRequest, blockchain: Arc<Blockchain>| async move {
+            if !dev {
+                return Err(warp::reject::custom(ChainError::SigningDisabled));
+            }
+            let transaction = sign_transaction(request, &blockchain).map_err(warp::reject::custom)?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&transaction))
+        });
+
+    let nonce = warp::path("nonce")
+        .and(warp::get())
+        .and(warp::path::param::<String>())
+        .and(blockchain_filter.clone())
+        .map(|address: String, blockchain: Arc<Blockchain>| {
+            let nonce = blockchain.get_next_nonce(&address);
+            warp::reply::json(&NonceResponse { address, nonce })
+        });
+
+    let validators = warp::path("validators")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .map(|blockchain: Arc<Blockchain>| warp::reply::json(&blockchain.get_validators()));
+
+    let slashing = warp::path("slashing")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .map(|blockchain: Arc<Blockchain>| warp::reply::json(&blockchain.get_slash_events()));
+
+    let supply = warp::path("supply")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .map(|blockchain: Arc<Blockchain>| warp::reply::json(&SupplyResponse::new(&blockchain)));
+
+    let mempool = warp::path("mempool")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .map(|blockchain: Arc<Blockchain>| warp::reply::json(&blockchain.mempool_status()));
+
     let routes = new_address
+        .or(nonce)
+        .or(validators)
+        .or(slashing)
+        .or(mempool)
+        .or(supply)
+        .or(sign_transaction)
         .or(balance)
         .or(transaction)
         .or(transaction_details)
+        .or(block_details)
+        .or(latest_block)
+        .or(block_by_height)
+        .or(block_by_hash)
+        .or(blocks)
+        .or(address_transactions)
+        .or(rpc)
+        .or(ws)
         .or(transfer_tokens)
-        .or(airdrop_tokens);
-    
-    println!("Starting MOHSIN CHAIN on port 3030");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+        .or(airdrop_tokens)
+        .or(faucet_status)
+        .or(p2p_transaction)
+        .or(p2p_block)
+        .or(p2p_vote)
+        .or(p2p_status)
+        .or(p2p_blocks)
+        .recover(handle_rejection);
+
+    info!("Database: {}, peers: {:?}", db_path, network_peers);
+    println!("Starting MOHSIN CHAIN on port {}", args.port);
+    warp::serve(routes).run(([127, 0, 0, 1], args.port)).await;
 }
 
 #[derive(Deserialize)]
@@ -354,31 +540,253 @@ struct TransferRequest {
     from: String,
     to: String,
     amount: u64,
+    fee: Option<u64>, // Defaults to the node's minimum fee
+    nonce: u64,
+    public_key: String,
+    signature: String,
 }
 
+/// Signing helper for testing with curl on a `--dev` node, in the same spirit as `/new_address`.
 #[derive(Deserialize)]
+struct SignRequest {
+    private_key: String,
+    #[serde(default)]
+    kind: TransactionKind,
+    recipient: String,
+    amount: u64,
+    fee: Option<u64>,   // Defaults to the node's minimum fee
+    nonce: Option<u64>, // Defaults to the sender's next expected nonce
+    evidence: Option<DoubleSignEvidence>,
+}
+
+#[derive(Serialize)]
+struct NonceResponse {
+    address: String,
+    nonce: u64,
+}
+
+#[derive(Serialize)]
+struct AirdropResponse {
+    address: String,
+    amount: u64,
+    balance: u64,  // Committed balance; the airdrop only counts once a block includes it
+    tx_id: String, // The faucet's transfer, or the mint transaction for airdrops with the admin key
+}
+
+/// Why a request was rejected. `code` is stable, `message` is for people.
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+}
+
+/// Pagination of explorer listings. `from` is a block height.
+#[derive(Deserialize)]
+struct PageQuery {
+    from: Option<u64>,
+    limit: Option<u64>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockRangeQuery {
+    from: u64,
+    limit: u64,
+}
+
+#[derive(Serialize, Deserialize)]
 struct AirdropRequest {
     address: String,
     amount: u64,
 }
 
-fn generate_key_pair() -> KeyPair {
-    let private_key = rand::thread_rng().gen::<[u8; 32]>();
-    let public_key = Sha256::digest(&private_key);
-    KeyPair {
-        private_key: encode(private_key),
-        public_key: encode(public_key),
+/// Private keys from `--validator-key` and `--validator-key-file`, or, if
+/// neither is given, those of the development validators in `dev_validators`.
+fn load_validator_keys(mut keys: Vec<String>, key_file: Option<&str>, dev_validators: Vec<u32>) -> Vec<String> {
+    if let Some(path) = key_file {
+        let contents = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read validator key file {}: {}", path, e));
+        keys.extend(contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(String::from));
     }
+    if keys.is_empty() {
+        let indices: Vec<u32> = if dev_validators.is_empty() { (0..pos::DEV_VALIDATORS).collect() } else { dev_validators };
+        warn!("Producing blocks for development validators {:?}, whose private keys are public. Use --validator-key-file outside of development", indices);
+        return indices.into_iter().map(pos::dev_validator_key).collect();
+    }
+    for key in &keys {
+        crypto::public_key_from_private_key(key).unwrap_or_else(|e| panic!("Invalid validator key: {}", e));
+    }
+    keys
 }
 
-fn generate_transaction_id() -> String {
-    let mut rng = rand::thread_rng();
-    (0..8).map(|_| rng.sample(rand::distributions::Alphanumeric) as char).collect()
+/// Signs `request`, filling in the sender's next nonce and the node's minimum fee where they are omitted.
+fn sign_transaction(request: SignRequest, blockchain: &Blockchain) -> Result<Transaction, ChainError> {
+    let sender = crypto::address_from_public_key(&crypto::public_key_from_private_key(&request.private_key)?)?;
+    let fee = request.fee.unwrap_or_else(|| blockchain.min_fee());
+    let nonce = request.nonce.unwrap_or_else(|| blockchain.get_next_nonce(&sender));
+    sign(request, fee, nonce)
 }
 
-async fn get_transaction(id: String, blockchain: Arc<Blockchain>) -> Result<impl warp::Reply, warp::Rejection> {
-    match blockchain.get_transaction(&id) {
-        Some(transaction) => Ok(warp::reply::json(&transaction)),
-        None => Ok(warp::reply::json(&format!("Transaction with ID {} not found", id))),
+fn sign(request: SignRequest, fee: u64, nonce: u64) -> Result<Transaction, ChainError> {
+    let SignRequest { private_key, kind, recipient, amount, evidence, .. } = request;
+    let public_key = crypto::public_key_from_private_key(&private_key)?;
+    let mut transaction = Transaction {
+        id: String::new(),
+        kind,
+        sender: crypto::address_from_public_key(&public_key)?,
+        recipient,
+        amount,
+        fee,
+        nonce,
+        public_key,
+        signature: String::new(),
+        evidence,
+    };
+    transaction.sign(&private_key)?;
+    Ok(transaction)
+}
+
+/// `node sign`: signs a transaction with a private key that never leaves this machine and prints it.
+fn sign_offline(args: SignArgs) {
+    let read = |path: &str| std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("Failed to read {}: {}", path, e)));
+    let private_key = match &args.key_file {
+        Some(path) => read(path),
+        None => std::io::read_to_string(std::io::stdin()).unwrap_or_else(|e| fail(format!("Failed to read the private key: {}", e))),
+    };
+    let evidence = args.evidence_file.as_deref().map(|path| {
+        serde_json::from_str(&read(path)).unwrap_or_else(|e| fail(format!("Invalid evidence in {}: {}", path, e)))
+    });
+    let request = SignRequest {
+        private_key: private_key.trim().to_string(),
+        kind: args.kind,
+        recipient: args.recipient,
+        amount: args.amount,
+        fee: None,
+        nonce: None,
+        evidence,
+    };
+    match sign(request, args.fee, args.nonce) {
+        Ok(transaction) => println!("{}", serde_json::to_string_pretty(&transaction).expect("Failed to serialize transaction")),
+        Err(e) => fail(e.to_string()),
     }
 }
+
+fn parse_kind(kind: &str) -> Result<TransactionKind, String> {
+    serde_json::from_value(serde_json::Value::String(kind.to_string())).map_err(|_| format!("unknown transaction kind {}", kind))
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+/// Signs and submits a transfer from the faucet account, within the faucet's limits.
+fn send_from_faucet(request: &AirdropRequest, faucet: &Faucet, blockchain: &Blockchain) -> Result<Transaction, ChainError> {
+    let now = Utc::now().timestamp() as u64;
+    faucet.drip(&request.address, request.amount, now, || {
+        let transfer = SignRequest {
+            private_key: faucet.private_key().to_string(),
+            kind: TransactionKind::Transfer,
+            recipient: request.address.clone(),
+            amount: request.amount,
+            fee: None,
+            nonce: None,
+            evidence: None,
+        };
+        let transaction = sign_transaction(transfer, blockchain)?;
+        blockchain.add_transaction(transaction.clone())?;
+        Ok(transaction)
+    })
+}
+
+/// Signs and submits a mint transaction from the mint authority.
+fn mint(request: &AirdropRequest, mint_key: &str, blockchain: &Blockchain) -> Result<Transaction, ChainError> {
+    let mint = SignRequest {
+        private_key: mint_key.to_string(),
+        kind: TransactionKind::Mint,
+        recipient: request.address.clone(),
+        amount: request.amount,
+        fee: None,
+        nonce: None,
+        evidence: None,
+    };
+    let transaction = sign_transaction(mint, blockchain)?;
+    blockchain.add_transaction(transaction.clone())?;
+    Ok(transaction)
+}
+
+/// Submits an evidence transaction against a validator that signed two blocks for one slot.
+fn report_double_sign(evidence: DoubleSignEvidence, reporter_key: &str, blockchain: &Blockchain, network: &Network) {
+    let validator = evidence.first.validator.clone();
+    let request = SignRequest {
+        private_key: reporter_key.to_string(),
+        kind: TransactionKind::Evidence,
+        recipient: validator.clone(),
+        amount: 0,
+        fee: None,
+        nonce: None,
+        evidence: Some(evidence),
+    };
+    let result = sign_transaction(request, blockchain).and_then(|transaction| {
+        blockchain.add_transaction(transaction.clone())?;
+        network.broadcast_transaction(&transaction);
+        Ok(transaction)
+    });
+    match result {
+        Ok(transaction) => warn!("Reported double signing by {} in transaction {}", validator, transaction.id),
+        Err(e) => debug!("Could not report double signing by {}: {}", validator, e),
+    }
+}
+
+/// Replies with `body`, or with the sentence from `message` when the node runs with `--legacy-responses`.
+fn respond<T: Serialize>(legacy: bool, body: &T, message: impl FnOnce() -> String) -> warp::reply::Json {
+    if legacy {
+        warp::reply::json(&message())
+    } else {
+        warp::reply::json(body)
+    }
+}
+
+async fn get_transaction(id: String, blockchain: Arc<Blockchain>) -> Result<impl warp::Reply, warp::Rejection> {
+    let details = TransactionDetails::lookup(&id, &blockchain).ok_or_else(|| warp::reject::custom(ChainError::TransactionNotFound(id)))?;
+    Ok(warp::reply::json(&details))
+}
+
+/// Rejects peer-to-peer requests from nodes that do not send our genesis hash.
+/// `/p2p/status` stays open so that peers can see which chain we are on.
+fn same_genesis(genesis_hash: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>(GENESIS_HEADER)
+        .and_then(move |found: Option<String>| {
+            let expected = genesis_hash.clone();
+            async move {
+                match found {
+                    Some(found) if found == expected => Ok(()),
+                    found => Err(warp::reject::custom(ChainError::GenesisMismatch { expected, found })),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns every rejection into a status code and an `ErrorResponse` body.
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let (status, code, message) = if let Some(e) = rejection.find::<ChainError>() {
+        (e.status_code(), e.code(), e.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "No such endpoint".to_string())
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed".to_string())
+    } else {
+        warn!("Unhandled rejection: {:?}", rejection);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&ErrorResponse { code, message }), status))
+}
@@ -0,0 +1,457 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::blockchain::{DroppedTransaction, Transaction};
+use crate::error::ChainError;
+use crate::state::Account;
+
+/// Lowest fee the mempool accepts unless configured otherwise.
+pub const DEFAULT_MIN_FEE: u64 = 1;
+/// Dropped transactions `/mempool` lists.
+const RECENT_DROPPED: usize = 100;
+
+/// Admission and eviction rules for pending transactions.
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub max_size: usize,       // Pending transactions across all senders
+    pub max_per_sender: usize, // Pending transactions from one sender
+    pub ttl: u64,              // Seconds a transaction may wait before it expires
+    pub min_fee: u64,          // Lowest fee accepted; blocks may include any fee
+    pub replacement_bump: u64, // Percent a replacement must raise the fee by
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_size: 10_000,
+            max_per_sender: 64,
+            ttl: 600,
+            min_fee: DEFAULT_MIN_FEE,
+            replacement_bump: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PendingTransaction {
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub size: usize,
+    pub added_at: u64, // Unix time the transaction entered the mempool
+}
+
+/// Counts and contents of the mempool, for `/mempool`.
+#[derive(Serialize, Debug, Clone)]
+pub struct MempoolStatus {
+    pub count: usize,
+    pub bytes: usize,
+    pub senders: usize,
+    pub max_size: usize,
+    pub pending: Vec<PendingTransaction>,          // Highest fee per byte first
+    pub dropped: u64,                              // Dropped since the node started
+    pub dropped_by_reason: BTreeMap<String, u64>,  // Error code -> count
+    pub recently_dropped: Vec<DroppedTransaction>, // Newest first
+}
+
+/// Transactions waiting for a block, indexed by id and by sender and nonce.
+/// A sender's pending nonces are always contiguous from its account nonce,
+/// and its balance covers all of them together, so every pending transaction
+/// can eventually be included. Transactions
+/// that leave without being included are returned to the caller, which
+/// records them as dropped.
+pub struct Mempool {
+    config: MempoolConfig,
+    transactions: HashMap<String, PendingTransaction>, // Transaction ID -> transaction
+    senders: HashMap<String, BTreeMap<u64, String>>,   // Sender -> nonce -> transaction ID
+    dropped: u64,
+    dropped_by_reason: BTreeMap<String, u64>,
+    recently_dropped: VecDeque<DroppedTransaction>,
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Mempool {
+            config,
+            transactions: HashMap::new(),
+            senders: HashMap::new(),
+            dropped: 0,
+            dropped_by_reason: BTreeMap::new(),
+            recently_dropped: VecDeque::new(),
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.transactions.contains_key(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transaction> {
+        self.transactions.get(id).map(|pending| &pending.transaction)
+    }
+
+    /// Next nonce for `sender` after its pending transactions.
+    pub fn next_nonce(&self, sender: &str, account_nonce: u64) -> u64 {
+        self.senders
+            .get(sender)
+            .and_then(|nonces| nonces.keys().next_back())
+            .map_or(account_nonce, |last| last + 1)
+    }
+
+    /// What `sender`'s pending transactions, other than `except`, take out of its balance.
+    fn pending_debits(&self, sender: &str, except: Option<&str>) -> u64 {
+        self.senders
+            .get(sender)
+            .into_iter()
+            .flat_map(|nonces| nonces.values())
+            .filter(|id| Some(id.as_str()) != except)
+            .map(|id| self.transactions[id].transaction.debit().unwrap_or(u64::MAX))
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Adds `transaction`, replacing a pending one with the same sender and
+    /// nonce if it pays enough more, or evicting the pending transaction with
+    /// the lowest fee per byte if the mempool is full. `account` is the
+    /// sender's committed account, whose balance must cover this transaction
+    /// on top of the sender's other pending ones. Returns the transactions
+    /// that had to leave.
+    pub fn insert(&mut self, transaction: Transaction, account: &Account, now: u64) -> Result<Vec<(Transaction, ChainError)>, ChainError> {
+        let account_nonce = account.nonce;
+        if self.transactions.contains_key(&transaction.id) {
+            return Err(ChainError::DuplicateTransaction(transaction.id.clone()));
+        }
+        if transaction.fee < self.config.min_fee {
+            return Err(ChainError::FeeTooLow { minimum: self.config.min_fee, found: transaction.fee });
+        }
+        if transaction.nonce < account_nonce {
+            return Err(ChainError::NonceAlreadyUsed { expected: self.next_nonce(&transaction.sender, account_nonce), found: transaction.nonce });
+        }
+
+        let replaced = self.senders.get(&transaction.sender).and_then(|nonces| nonces.get(&transaction.nonce)).cloned();
+        let debit = transaction.debit()?;
+        let available = account.balance.saturating_sub(self.pending_debits(&transaction.sender, replaced.as_deref()));
+        if available < debit {
+            return Err(ChainError::InsufficientFunds { available, required: debit });
+        }
+
+        if let Some(replaced) = replaced {
+            let old_fee = self.transactions[&replaced].transaction.fee as u128;
+            let minimum = (old_fee + old_fee * self.config.replacement_bump as u128 / 100).max(old_fee + 1);
+            if (transaction.fee as u128) < minimum {
+                let minimum = minimum.min(u64::MAX as u128) as u64;
+                return Err(ChainError::ReplacementUnderpriced { minimum, found: transaction.fee });
+            }
+            let old = self.remove(&replaced).unwrap();
+            let id = transaction.id.clone();
+            self.add(transaction, now);
+            return Ok(vec![(old, ChainError::ReplacedByFee(id))]);
+        }
+
+        let expected = self.next_nonce(&transaction.sender, account_nonce);
+        if transaction.nonce > expected {
+            return Err(ChainError::NonceTooHigh { expected, found: transaction.nonce });
+        }
+        if self.senders.get(&transaction.sender).map_or(0, |nonces| nonces.len()) >= self.config.max_per_sender {
+            return Err(ChainError::TooManyPending { limit: self.config.max_per_sender });
+        }
+
+        let mut evicted = Vec::new();
+        if self.transactions.len() >= self.config.max_size {
+            // Only a sender's last transaction can go without stranding the ones after it
+            let cheapest = self.senders
+                .values()
+                .filter_map(|nonces| nonces.values().next_back())
+                .map(|id| &self.transactions[id].transaction)
+                .reduce(|cheapest, tx| if cheapest.pays_more_per_byte_than(tx) { tx } else { cheapest });
+            match cheapest {
+                Some(cheapest) if transaction.pays_more_per_byte_than(cheapest) => {
+                    let id = cheapest.id.clone();
+                    evicted.push((self.remove(&id).unwrap(), ChainError::Evicted));
+                }
+                _ => return Err(ChainError::MempoolFull),
+            }
+        }
+        self.add(transaction, now);
+        Ok(evicted)
+    }
+
+    fn add(&mut self, transaction: Transaction, now: u64) {
+        self.senders
+            .entry(transaction.sender.clone())
+            .or_default()
+            .insert(transaction.nonce, transaction.id.clone());
+        let pending = PendingTransaction { size: transaction.size(), added_at: now, transaction };
+        self.transactions.insert(pending.transaction.id.clone(), pending);
+    }
+
+    /// Removes one transaction, leaving the sender's others in place.
+    pub fn remove(&mut self, id: &str) -> Option<Transaction> {
+        let pending = self.transactions.remove(id)?;
+        let tx = pending.transaction;
+        if let Some(nonces) = self.senders.get_mut(&tx.sender) {
+            nonces.remove(&tx.nonce);
+            if nonces.is_empty() {
+                self.senders.remove(&tx.sender);
+            }
+        }
+        Some(tx)
+    }
+
+    /// Removes the transactions a block included, and any other pending
+    /// transaction of theirs whose nonce the block used up. `account_nonce`
+    /// gives a sender's nonce after the block.
+    pub fn remove_included(&mut self, included: &[Transaction], account_nonce: impl Fn(&str) -> u64) -> Vec<(Transaction, ChainError)> {
+        for tx in included {
+            self.remove(&tx.id);
+        }
+        let mut stale = Vec::new();
+        for tx in included {
+            let nonce = account_nonce(&tx.sender);
+            let used: Vec<String> = self.senders
+                .get(&tx.sender)
+                .map(|nonces| nonces.range(..nonce).map(|(_, id)| id.clone()).collect())
+                .unwrap_or_default();
+            for id in used {
+                let tx = self.remove(&id).unwrap();
+                let expected = nonce;
+                let found = tx.nonce;
+                stale.push((tx, ChainError::NonceAlreadyUsed { expected, found }));
+            }
+        }
+        stale
+    }
+
+    /// Removes transactions that waited longer than the TTL, and the later
+    /// transactions of their senders, which could no longer be included.
+    pub fn expire(&mut self, now: u64) -> Vec<(Transaction, ChainError)> {
+        let ttl = self.config.ttl;
+        let mut expired = Vec::new();
+        let senders: Vec<String> = self.senders.keys().cloned().collect();
+        for sender in senders {
+            let ids: Vec<String> = self.senders[&sender].values().cloned().collect();
+            let Some(first_expired) = ids.iter().position(|id| self.transactions[id].added_at.saturating_add(ttl) <= now) else { continue };
+            let expected = self.transactions[&ids[first_expired]].transaction.nonce;
+            for (position, id) in ids.iter().enumerate().skip(first_expired) {
+                let tx = self.remove(id).unwrap();
+                let reason = if position == first_expired {
+                    ChainError::Expired
+                } else {
+                    ChainError::NonceTooHigh { expected, found: tx.nonce } // Its predecessor expired
+                };
+                expired.push((tx, reason));
+            }
+        }
+        expired
+    }
+
+    /// Transactions for a block, by fee per byte, highest first, until the
+    /// block holds `max_count` transactions or `max_bytes` of them. A sender's
+    /// transactions are taken in nonce order, so a transaction only competes
+    /// once the one before it is in.
+    pub fn select(&self, max_count: usize, max_bytes: usize) -> Vec<Transaction> {
+        let mut queues: HashMap<&str, Vec<&Transaction>> = self.senders
+            .iter()
+            .map(|(sender, nonces)| {
+                let queue = nonces.values().rev().map(|id| &self.transactions[id].transaction).collect(); // Lowest nonce last, to pop first
+                (sender.as_str(), queue)
+            })
+            .collect();
+
+        let mut selected = Vec::new();
+        let mut block_bytes = 0;
+        while selected.len() < max_count {
+            let best = queues
+                .iter()
+                .filter_map(|(sender, queue)| queue.last().map(|tx| (*sender, *tx)))
+                .reduce(|best, candidate| if candidate.1.pays_more_per_byte_than(best.1) { candidate } else { best });
+            let Some((sender, tx)) = best else { break };
+            let tx_bytes = self.transactions[&tx.id].size;
+            if block_bytes + tx_bytes > max_bytes {
+                queues.remove(sender); // Its later nonces cannot go in without this one
+                continue;
+            }
+            block_bytes += tx_bytes;
+            selected.push(tx.clone());
+            queues.get_mut(sender).unwrap().pop();
+        }
+        selected
+    }
+
+    /// Remembers a dropped transaction for `status`.
+    pub fn note_dropped(&mut self, dropped: DroppedTransaction) {
+        self.dropped += 1;
+        *self.dropped_by_reason.entry(dropped.reason.code.clone()).or_default() += 1;
+        self.recently_dropped.push_front(dropped);
+        self.recently_dropped.truncate(RECENT_DROPPED);
+    }
+
+    pub fn status(&self) -> MempoolStatus {
+        let mut pending: Vec<PendingTransaction> = self.transactions.values().cloned().collect();
+        pending.sort_by(|a, b| {
+            let (a_rate, b_rate) = (a.transaction.fee as u128 * b.size as u128, b.transaction.fee as u128 * a.size as u128);
+            b_rate
+                .cmp(&a_rate)
+                .then(a.added_at.cmp(&b.added_at))
+                .then_with(|| (&a.transaction.sender, a.transaction.nonce).cmp(&(&b.transaction.sender, b.transaction.nonce)))
+        });
+        MempoolStatus {
+            count: self.transactions.len(),
+            bytes: self.transactions.values().map(|pending| pending.size).sum(),
+            senders: self.senders.len(),
+            max_size: self.config.max_size,
+            pending,
+            dropped: self.dropped,
+            dropped_by_reason: self.dropped_by_reason.clone(),
+            recently_dropped: self.recently_dropped.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::TransactionKind;
+
+    fn tx(sender: &str, nonce: u64, amount: u64, fee: u64) -> Transaction {
+        Transaction {
+            id: format!("{}-{}-{}", sender, nonce, fee),
+            kind: TransactionKind::Transfer,
+            sender: sender.to_string(),
+            recipient: "recipient".to_string(),
+            amount,
+            fee,
+            nonce,
+            public_key: String::new(),
+            signature: String::new(),
+            evidence: None,
+        }
+    }
+
+    fn account(balance: u64, nonce: u64) -> Account {
+        Account { balance, nonce }
+    }
+
+    fn mempool(max_size: usize) -> Mempool {
+        Mempool::new(MempoolConfig { max_size, ..MempoolConfig::default() })
+    }
+
+    /// `Mempool::insert`, with the transactions that had to leave given by id.
+    fn insert(mempool: &mut Mempool, transaction: Transaction, account: &Account) -> Result<Vec<(String, ChainError)>, ChainError> {
+        mempool
+            .insert(transaction, account, 0)
+            .map(|left| left.into_iter().map(|(tx, reason)| (tx.id, reason)).collect())
+    }
+
+    #[test]
+    fn nonces_stay_contiguous_from_the_account_nonce() {
+        let mut mempool = mempool(10);
+        let funded = account(1_000, 3);
+
+        assert_eq!(insert(&mut mempool, tx("a", 2, 10, 1), &funded), Err(ChainError::NonceAlreadyUsed { expected: 3, found: 2 }));
+        assert_eq!(insert(&mut mempool, tx("a", 4, 10, 1), &funded), Err(ChainError::NonceTooHigh { expected: 3, found: 4 }));
+        assert_eq!(insert(&mut mempool, tx("a", 3, 10, 1), &funded), Ok(vec![]));
+        assert_eq!(insert(&mut mempool, tx("a", 5, 10, 1), &funded), Err(ChainError::NonceTooHigh { expected: 4, found: 5 }));
+        assert_eq!(insert(&mut mempool, tx("a", 4, 10, 1), &funded), Ok(vec![]));
+        assert_eq!(mempool.next_nonce("a", 3), 5);
+        assert_eq!(mempool.next_nonce("b", 7), 7);
+    }
+
+    #[test]
+    fn expiry_takes_the_later_nonces_along() {
+        let mut mempool = mempool(10);
+        let funded = account(1_000, 0);
+        mempool.insert(tx("a", 0, 10, 1), &funded, 0).unwrap();
+        mempool.insert(tx("a", 1, 10, 1), &funded, 500).unwrap();
+        mempool.insert(tx("b", 0, 10, 1), &funded, 500).unwrap();
+
+        let expired: Vec<(String, ChainError)> = mempool.expire(600).into_iter().map(|(tx, reason)| (tx.id, reason)).collect();
+        assert_eq!(expired, [
+            ("a-0-1".to_string(), ChainError::Expired),
+            ("a-1-1".to_string(), ChainError::NonceTooHigh { expected: 0, found: 1 }),
+        ]);
+        assert!(mempool.contains("b-0-1"));
+    }
+
+    #[test]
+    fn replacement_must_raise_the_fee_by_the_bump() {
+        let mut mempool = mempool(10);
+        let funded = account(1_000, 0);
+        insert(&mut mempool, tx("a", 0, 10, 50), &funded).unwrap();
+
+        assert_eq!(insert(&mut mempool, tx("a", 0, 10, 54), &funded), Err(ChainError::ReplacementUnderpriced { minimum: 55, found: 54 }));
+        assert_eq!(
+            insert(&mut mempool, tx("a", 0, 10, 55), &funded),
+            Ok(vec![("a-0-50".to_string(), ChainError::ReplacedByFee("a-0-55".to_string()))])
+        );
+        assert!(!mempool.contains("a-0-50"));
+        assert!(mempool.contains("a-0-55"));
+    }
+
+    #[test]
+    fn replacement_raises_a_low_fee_by_at_least_one() {
+        let mut mempool = mempool(10);
+        let funded = account(1_000, 0);
+        insert(&mut mempool, tx("a", 0, 10, 1), &funded).unwrap();
+
+        assert_eq!(insert(&mut mempool, tx("a", 0, 10, 1), &funded), Err(ChainError::DuplicateTransaction("a-0-1".to_string())));
+        let same_fee = Transaction { id: "other".to_string(), ..tx("a", 0, 10, 1) };
+        assert_eq!(insert(&mut mempool, same_fee, &funded), Err(ChainError::ReplacementUnderpriced { minimum: 2, found: 1 }));
+    }
+
+    #[test]
+    fn replacement_minimum_does_not_overflow() {
+        let mut mempool = mempool(10);
+        let rich = account(u64::MAX, 0);
+        insert(&mut mempool, tx("a", 0, 0, u64::MAX - 1), &rich).unwrap();
+
+        assert_eq!(
+            insert(&mut mempool, tx("a", 0, 0, u64::MAX), &rich),
+            Err(ChainError::ReplacementUnderpriced { minimum: u64::MAX, found: u64::MAX })
+        );
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_cheapest_last_transaction_of_a_sender() {
+        let mut mempool = mempool(3);
+        let funded = account(1_000, 0);
+        insert(&mut mempool, tx("a", 0, 10, 1), &funded).unwrap();
+        insert(&mut mempool, tx("a", 1, 10, 9), &funded).unwrap();
+        insert(&mut mempool, tx("b", 0, 10, 3), &funded).unwrap();
+
+        // a-0-1 pays least, but a-1-9 depends on it
+        assert_eq!(insert(&mut mempool, tx("c", 0, 10, 5), &funded), Ok(vec![("b-0-3".to_string(), ChainError::Evicted)]));
+        assert_eq!(insert(&mut mempool, tx("d", 0, 10, 4), &funded), Err(ChainError::MempoolFull));
+        assert!(mempool.contains("a-0-1"));
+        assert!(mempool.contains("c-0-5"));
+    }
+
+    #[test]
+    fn balance_must_cover_every_pending_transaction() {
+        let mut mempool = mempool(10);
+        let funded = account(25, 0);
+        insert(&mut mempool, tx("a", 0, 10, 1), &funded).unwrap();
+        insert(&mut mempool, tx("a", 1, 10, 1), &funded).unwrap();
+
+        assert_eq!(insert(&mut mempool, tx("a", 2, 10, 1), &funded), Err(ChainError::InsufficientFunds { available: 3, required: 11 }));
+        assert_eq!(insert(&mut mempool, tx("a", 2, 2, 1), &funded), Ok(vec![]));
+        // A replacement only has to fit next to the other pending transactions
+        assert_eq!(insert(&mut mempool, tx("a", 2, 1, 2), &funded).map(|left| left.len()), Ok(1));
+        assert_eq!(insert(&mut mempool, tx("a", 2, 1, 3), &funded), Err(ChainError::InsufficientFunds { available: 3, required: 4 }));
+    }
+
+    #[test]
+    fn unfunded_sender_cannot_evict() {
+        let mut mempool = mempool(1);
+        insert(&mut mempool, tx("a", 0, 10, 1), &account(100, 0)).unwrap();
+
+        assert_eq!(insert(&mut mempool, tx("b", 0, 0, 9), &account(0, 0)), Err(ChainError::InsufficientFunds { available: 0, required: 9 }));
+        assert!(mempool.contains("a-0-1"));
+    }
+
+    #[test]
+    fn amount_plus_fee_overflow_is_rejected() {
+        let mut mempool = mempool(10);
+        assert_eq!(
+            insert(&mut mempool, tx("a", 0, u64::MAX, 1), &account(u64::MAX, 0)),
+            Err(ChainError::InvalidAmount("Amount plus fee overflows"))
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use hex::encode;
+use sha2::{Sha256, Digest};
+
+const LEAF: u8 = 0x00; // Domain prefix of leaf hashes
+const NODE: u8 = 0x01; // Domain prefix of inner node hashes
+
+/// Merkle root over transaction hashes (`Transaction::hash`). Leaves are
+/// SHA-256(0x00 || hash) and inner nodes SHA-256(0x01 || left || right), so
+/// a leaf can never pass for a node; an odd node at any level is promoted to
+/// the next level unchanged, so no two lists of hashes share a root. An
+/// empty block has an all-zero root.
+pub fn merkle_root<S: AsRef<str>>(hashes: &[S]) -> String {
+    if hashes.is_empty() {
+        return "0".repeat(64);
+    }
+
+    let mut level: Vec<[u8; 32]> = hashes
+        .iter()
+        .map(|hash| {
+            let mut hasher = Sha256::new();
+            hasher.update([LEAF]);
+            hasher.update(hash.as_ref().as_bytes());
+            hasher.finalize().into()
+        })
+        .collect();
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([NODE]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [odd] => *odd,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+
+    encode(level[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(bytes: &[u8]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    fn leaf(hash: &str) -> [u8; 32] {
+        sha256(&[&[LEAF], hash.as_bytes()].concat())
+    }
+
+    fn pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        sha256(&[&[NODE][..], &left, &right].concat())
+    }
+
+    #[test]
+    fn empty_root_is_all_zeros() {
+        assert_eq!(merkle_root::<&str>(&[]), "0".repeat(64));
+    }
+
+    #[test]
+    fn single_leaf_root_is_its_leaf_hash() {
+        assert_eq!(merkle_root(&["a"]), encode(leaf("a")));
+    }
+
+    #[test]
+    fn odd_node_is_promoted_unchanged() {
+        let expected = pair(pair(leaf("a"), leaf("b")), leaf("c"));
+        assert_eq!(merkle_root(&["a", "b", "c"]), encode(expected));
+    }
+
+    #[test]
+    fn duplicated_last_leaf_changes_the_root() {
+        assert_ne!(merkle_root(&["a", "b", "c"]), merkle_root(&["a", "b", "c", "c"]));
+        assert_ne!(merkle_root(&["a"]), merkle_root(&["a", "a"]));
+    }
+
+    #[test]
+    fn root_commits_to_order_and_contents() {
+        let root = merkle_root(&["a", "b"]);
+        assert_ne!(root, merkle_root(&["b", "a"]));
+        assert_ne!(root, merkle_root(&["a", "c"]));
+        assert_ne!(root, merkle_root(&["a", "b", "b"]));
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Serialize, Deserialize};
+
+use crate::crypto;
+
+/// How new tokens are issued, part of the genesis. Tokens only come from the
+/// genesis allocation, block rewards and `mint` transactions, so everything
+/// issued after genesis is recorded in blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MonetaryPolicy {
+    pub block_reward: u64,       // Paid to the validator of each block before the first reduction
+    #[serde(default)]
+    pub empty_block_bonus: u64,  // Added to the reward of a block without transactions; pays validators to leave transactions out
+    pub reduction_interval: u64, // Blocks between reward reductions, 0 to never reduce
+    pub reduction_percent: u64,  // Cut at each reduction; 50 halves the reward
+    #[serde(default)]
+    pub mint_authority: Option<String>, // Address allowed to send `mint` transactions, none to disable minting
+}
+
+impl Default for MonetaryPolicy {
+    fn default() -> Self {
+        MonetaryPolicy {
+            block_reward: 1,
+            empty_block_bonus: 0,
+            reduction_interval: 0,
+            reduction_percent: 50,
+            mint_authority: None,
+        }
+    }
+}
+
+impl MonetaryPolicy {
+    /// Newly issued tokens paid to the validator of the block at `height`:
+    /// the block reward, plus the bonus for an empty block, cut by
+    /// `reduction_percent` once for every `reduction_interval` blocks before it.
+    pub fn block_reward(&self, height: u64, transaction_count: usize) -> u64 {
+        let mut reward = self.block_reward;
+        if transaction_count == 0 {
+            reward = reward.saturating_add(self.empty_block_bonus);
+        }
+        if self.reduction_interval == 0 || self.reduction_percent == 0 {
+            return reward;
+        }
+        let reductions = height.saturating_sub(1) / self.reduction_interval;
+        for _ in 0..reductions {
+            if reward == 0 {
+                break;
+            }
+            reward = (reward as u128 * (100 - self.reduction_percent.min(100)) as u128 / 100) as u64;
+        }
+        reward
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.reduction_percent > 100 {
+            return Err(format!("reduction_percent {} is above 100", self.reduction_percent));
+        }
+        Ok(())
+    }
+
+    /// Checks that `private_key` belongs to the mint authority, so that the
+    /// mint transactions it signs are accepted.
+    pub fn check_mint_key(&self, private_key: &str) -> Result<(), String> {
+        let authority = self.mint_authority.as_deref().ok_or("The genesis names no mint_authority")?;
+        let address = crypto::public_key_from_private_key(private_key)
+            .and_then(|public_key| crypto::address_from_public_key(&public_key))
+            .map_err(|e| e.to_string())?;
+        if address != authority {
+            return Err(format!("Key belongs to {}, but the mint authority is {}", address, authority));
+        }
+        Ok(())
+    }
+}
+
+/// Running totals of issuance, stored in the state so that rolling back a
+/// block rolls them back too.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Supply {
+    pub genesis: u64,       // Balances and stakes allocated by the genesis
+    pub block_rewards: u64, // Paid to block validators
+    pub minted: u64,        // Issued by `mint` transactions
+    pub burned: u64,        // Slashed stake
+}
+
+impl Supply {
+    /// Every token ever issued.
+    pub fn total_minted(&self) -> u64 {
+        self.genesis.saturating_add(self.block_rewards).saturating_add(self.minted)
+    }
+
+    /// Whether `amount` more tokens can be issued without the total
+    /// overflowing a u64. Every balance and stake is part of the total, so
+    /// none of them can overflow either.
+    pub fn can_issue(&self, amount: u64) -> bool {
+        self.total_minted().checked_add(amount).is_some()
+    }
+
+    /// Tokens in existence: balances, stakes, delegations and unbonding stake.
+    pub fn circulating(&self) -> u64 {
+        self.total_minted() - self.burned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(block_reward: u64, empty_block_bonus: u64, reduction_interval: u64) -> MonetaryPolicy {
+        MonetaryPolicy { block_reward, empty_block_bonus, reduction_interval, reduction_percent: 50, mint_authority: None }
+    }
+
+    #[test]
+    fn reward_halves_every_interval_until_it_reaches_zero() {
+        let policy = policy(8, 0, 3);
+        let rewards: Vec<u64> = (1..=14).map(|height| policy.block_reward(height, 1)).collect();
+        assert_eq!(rewards, [8, 8, 8, 4, 4, 4, 2, 2, 2, 1, 1, 1, 0, 0]);
+        assert_eq!(policy.block_reward(u64::MAX, 1), 0);
+    }
+
+    #[test]
+    fn reward_never_reduces_without_an_interval() {
+        let policy = policy(8, 0, 0);
+        assert_eq!(policy.block_reward(1, 1), 8);
+        assert_eq!(policy.block_reward(1_000_000, 1), 8);
+    }
+
+    #[test]
+    fn reduction_percent_is_applied_to_the_current_reward() {
+        let policy = MonetaryPolicy { reduction_percent: 10, ..policy(1_000, 0, 1) };
+        assert_eq!(policy.block_reward(1, 1), 1_000);
+        assert_eq!(policy.block_reward(2, 1), 900);
+        assert_eq!(policy.block_reward(3, 1), 810);
+    }
+
+    #[test]
+    fn empty_block_bonus_is_reduced_with_the_reward() {
+        let policy = policy(8, 2, 3);
+        assert_eq!(policy.block_reward(1, 0), 10);
+        assert_eq!(policy.block_reward(1, 1), 8);
+        assert_eq!(policy.block_reward(4, 0), 5);
+    }
+
+    #[test]
+    fn issuance_stops_before_the_total_overflows() {
+        let supply = Supply { genesis: u64::MAX - 10, block_rewards: 5, minted: 4, burned: 0 };
+        assert!(supply.can_issue(1));
+        assert!(!supply.can_issue(2));
+    }
+
+    #[test]
+    fn mint_key_must_belong_to_the_mint_authority() {
+        let key = crypto::generate_key_pair();
+        let other = crypto::generate_key_pair();
+        let minting = MonetaryPolicy { mint_authority: Some(key.address.clone()), ..policy(1, 0, 0) };
+        minting.check_mint_key(&key.private_key).unwrap();
+        assert!(minting.check_mint_key(&other.private_key).unwrap_err().contains(&other.address));
+        assert!(minting.check_mint_key("not a key").is_err());
+        assert!(policy(1, 0, 0).check_mint_key(&key.private_key).is_err());
+    }
+}
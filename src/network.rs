@@ -0,0 +1,182 @@
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use log::{debug, info, warn};
+
+use crate::blockchain::{Block, Blockchain, Transaction, BLOCK_TIME};
+use crate::finality::Vote;
+
+/// Largest number of blocks requested from or served to a peer at once.
+pub const SYNC_BATCH: u64 = 100;
+/// Header carrying the sender's genesis hash on every peer-to-peer request.
+pub const GENESIS_HEADER: &str = "x-genesis-hash";
+
+/// A node's view of its own chain tip, served at `/p2p/status`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainStatus {
+    pub chain_id: String,
+    pub genesis_hash: String,
+    pub height: u64, // Index of the tip block, 0 when only the genesis block exists
+    pub hash: String,
+    pub work: u128,  // Total work up to the tip, as weighed by the consensus engine
+}
+
+impl ChainStatus {
+    pub fn of(blockchain: &Blockchain) -> Self {
+        let tip = blockchain.get_last_block().expect("Missing genesis block");
+        ChainStatus {
+            chain_id: blockchain.chain_id().to_string(),
+            genesis_hash: blockchain.genesis_hash().to_string(),
+            height: tip.index,
+            hash: tip.hash,
+            work: blockchain.chain_work(),
+        }
+    }
+}
+
+/// Peer-to-peer gossip over HTTP. Every node is started with the full list of
+/// its peers, so a message is sent straight to each of them and never relayed.
+/// Requests carry our genesis hash, and peers on another genesis are ignored.
+pub struct Network {
+    peers: Vec<String>,
+    client: reqwest::Client,
+    genesis_hash: String,
+}
+
+impl Network {
+    pub fn new(peers: Vec<String>, genesis_hash: &str) -> Self {
+        Network {
+            peers,
+            client: reqwest::Client::new(),
+            genesis_hash: genesis_hash.to_string(),
+        }
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn broadcast_transaction(&self, transaction: &Transaction) {
+        self.broadcast("p2p/transaction", transaction);
+    }
+
+    pub fn broadcast_block(&self, block: &Block) {
+        self.broadcast("p2p/block", block);
+    }
+
+    pub fn broadcast_vote(&self, vote: &Vote) {
+        self.broadcast("p2p/vote", vote);
+    }
+
+    /// Polls every peer's tip and pulls in any chain that beats ours under the
+    /// fork-choice rule, whether it extends our chain or replaces part of it.
+    pub fn start_sync(self: Arc<Self>, blockchain: Blockchain) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(BLOCK_TIME).await;
+                for peer in &self.peers {
+                    if let Err(e) = self.sync_with(peer, &blockchain).await {
+                        debug!("Sync with {} failed: {}", peer, e);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn sync_with(&self, peer: &str, blockchain: &Blockchain) -> Result<(), String> {
+        let status: ChainStatus = self.fetch(peer, "p2p/status").await?;
+        if status.genesis_hash != self.genesis_hash {
+            warn!("Not syncing with {}: it is on chain {} with genesis {}", peer, status.chain_id, status.genesis_hash);
+            return Ok(());
+        }
+        let ours = ChainStatus::of(blockchain);
+        let peer_is_better = status.work > ours.work
+            || (status.work == ours.work && status.height > 0 && status.hash < ours.hash);
+        if !peer_is_better {
+            return Ok(());
+        }
+
+        // Walk back from the lower tip until the peer's block matches ours
+        let mut ancestor = ours.height.min(status.height);
+        while ancestor > 0 {
+            let from = ancestor.saturating_sub(SYNC_BATCH - 1).max(1);
+            let blocks = self.fetch_blocks(peer, from, ancestor - from + 1).await?;
+            let common = blocks.iter().rev().find(|block| {
+                blockchain.get_block(block.index).is_some_and(|ours| ours.hash == block.hash)
+            });
+            match common {
+                Some(block) => {
+                    ancestor = block.index;
+                    break;
+                }
+                None => ancestor = from - 1,
+            }
+        }
+
+        if ancestor < ours.height && blockchain.is_finalized(ancestor + 1) {
+            return Err(format!("{} forks from our chain below the finalized block {}", peer, blockchain.finalized_height()));
+        }
+
+        // Download the peer's blocks after the common ancestor a batch at a
+        // time, checking each batch's headers as it arrives. Switch to the
+        // branch as soon as it outweighs the blocks it replaces, then extend it
+        // batch by batch, so only the blocks needed to win are held at once
+        let mut parent = blockchain.get_block(ancestor).ok_or("Missing common ancestor")?;
+        let mut branch: Vec<Block> = Vec::new();
+        let mut next = ancestor + 1;
+        while next <= status.height {
+            let blocks = self.fetch_blocks(peer, next, SYNC_BATCH).await?;
+            let Some(last) = blocks.last().cloned() else { break };
+            blockchain.verify_branch(&parent, &branch, &blocks).map_err(|e| e.to_string())?;
+            next += blocks.len() as u64;
+            parent = last;
+            branch.extend(blocks);
+            if blockchain.is_better_branch(&branch) {
+                let (from, to) = (branch[0].index, parent.index);
+                blockchain.reorganize(std::mem::take(&mut branch)).map_err(|e| e.to_string())?;
+                info!("Synced blocks {}..={} from {}", from, to, peer);
+            }
+        }
+        Ok(())
+    }
+
+    async fn fetch_blocks(&self, peer: &str, from: u64, limit: u64) -> Result<Vec<Block>, String> {
+        self.fetch(peer, &format!("p2p/blocks?from={}&limit={}", from, limit)).await
+    }
+
+    async fn fetch<T: for<'de> Deserialize<'de>>(&self, peer: &str, path: &str) -> Result<T, String> {
+        self.client
+            .get(format!("http://{}/{}", peer, path))
+            .header(GENESIS_HEADER, &self.genesis_hash)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Sends `body` as JSON to `path` on every peer without waiting for the replies.
+    pub fn broadcast<T: Serialize>(&self, path: &str, body: &T) {
+        let body = serde_json::to_value(body).unwrap();
+        for peer in &self.peers {
+            let request = self.client
+                .post(format!("http://{}/{}", peer, path))
+                .header(GENESIS_HEADER, &self.genesis_hash)
+                .json(&body);
+            let peer = peer.clone();
+            let path = path.to_string();
+            tokio::spawn(async move {
+                match request.send().await {
+                    Ok(response) if response.status().is_success() => debug!("Sent /{} to {}: {}", path, peer, response.status()),
+                    Ok(response) => {
+                        let status = response.status();
+                        let body = response.text().await.unwrap_or_default();
+                        debug!("{} rejected /{} with {}: {}", peer, path, status, body);
+                    }
+                    Err(e) => warn!("Failed to send /{} to {}: {}", path, peer, e),
+                }
+            });
+        }
+    }
+}
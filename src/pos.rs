@@ -0,0 +1,270 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use chrono::Utc;
+use log::{debug, error};
+use std::time::Duration;
+
+use crate::blockchain::{Block, Blockchain, TransactionKind, BLOCK_TIME};
+use crate::consensus::ConsensusEngine;
+use crate::error::BlockError;
+
+/// Smallest own stake a validator needs to be picked as proposer.
+pub const MIN_VALIDATOR_STAKE: u64 = 100;
+/// Blocks between an unstake transaction and the tokens returning to the balance.
+pub const UNBONDING_PERIOD: u64 = 10;
+/// Blocks a slashed validator stays jailed before it can propose again.
+pub const JAIL_PERIOD: u64 = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidatorStatus {
+    Active,   // Eligible to propose blocks
+    Inactive, // Own stake below `MIN_VALIDATOR_STAKE`
+    Jailed,   // Slashed, not eligible until `jailed_until`
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Validator {
+    pub address: String,
+    pub stake: u64,     // Tokens locked from the validator's own balance
+    pub delegated: u64, // Tokens delegated to the validator by other accounts
+    pub status: ValidatorStatus,
+    #[serde(default)]
+    pub missed_slots: u64, // Consecutive slots it was picked for without producing a block
+    #[serde(default)]
+    pub jailed_until: u64, // Height at which a jailed validator is released
+    #[serde(default)]
+    pub unbonding: u64, // Own stake waiting out the unbonding period, still slashable
+}
+
+impl Validator {
+    pub fn new(address: &str) -> Self {
+        Validator {
+            address: address.to_string(),
+            stake: 0,
+            delegated: 0,
+            status: ValidatorStatus::Inactive,
+            missed_slots: 0,
+            jailed_until: 0,
+            unbonding: 0,
+        }
+    }
+
+    /// Stake that counts towards being picked as proposer.
+    pub fn total_stake(&self) -> u64 {
+        self.stake + self.delegated
+    }
+
+    /// Whether nothing is left to keep the record for: no stake, bonded or
+    /// unbonding, and no jail term. A record is kept until then, so that
+    /// unstaking cannot clear a jail term or escape slashing.
+    pub fn is_retired(&self) -> bool {
+        self.stake == 0 && self.delegated == 0 && self.unbonding == 0 && self.status != ValidatorStatus::Jailed
+    }
+
+    /// Recomputes the status from the stake. A jailed validator stays jailed.
+    pub fn update_status(&mut self) {
+        if self.status == ValidatorStatus::Jailed {
+            return;
+        }
+        self.status = if self.stake >= MIN_VALIDATOR_STAKE { ValidatorStatus::Active } else { ValidatorStatus::Inactive };
+    }
+}
+
+/// Slot a block with this timestamp belongs to. One block per slot at most.
+pub fn slot_at(timestamp: u64) -> u64 {
+    timestamp / BLOCK_TIME.as_secs()
+}
+
+/// Picks the proposer for `slot` on top of the block hashed `previous_hash`.
+/// Every node derives the same seed from the same inputs, and a validator's
+/// chance of being picked is proportional to its own plus delegated stake.
+/// Only active validators are picked. `validators` must be in the same order
+/// on every node (sorted by address).
+pub fn select_validator(validators: &[Validator], previous_hash: &str, slot: u64) -> Option<Validator> {
+    let validators: Vec<&Validator> = validators.iter().filter(|v| v.status == ValidatorStatus::Active).collect();
+    let total_stake: u64 = validators.iter().map(|v| v.total_stake()).sum();
+    if total_stake == 0 {
+        return None;
+    }
+
+    let seed = Sha256::digest(format!("{}:{}", previous_hash, slot));
+    let rand_value = u64::from_be_bytes(seed[..8].try_into().unwrap()) % total_stake;
+    let mut cumulative_stake = 0;
+    for validator in validators {
+        cumulative_stake += validator.total_stake();
+        if rand_value < cumulative_stake {
+            return Some((*validator).clone());
+        }
+    }
+    None
+}
+
+/// `percent` of `amount`, rounded down. Never overflows, whatever the amount.
+pub fn percent_of(amount: u64, percent: u64) -> u64 {
+    (amount as u128 * percent.min(100) as u128 / 100) as u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlashReason {
+    DoubleSign, // Signed two different blocks for the same height and slot
+    Downtime,   // Missed `ChainConfig::max_missed_slots` consecutive slots
+}
+
+impl SlashReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SlashReason::DoubleSign => "double_sign",
+            SlashReason::Downtime => "downtime",
+        }
+    }
+
+    /// Percentage of the validator's own stake that is burned.
+    pub fn slash_percent(self) -> u64 {
+        match self {
+            SlashReason::DoubleSign => 10,
+            SlashReason::Downtime => 1,
+        }
+    }
+}
+
+/// A validator losing stake and being jailed, recorded at the height of the offence.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlashEvent {
+    pub validator: String,
+    pub reason: SlashReason,
+    pub height: u64,      // Height of the double-signed blocks, or of the block that noticed the downtime
+    pub slashed: u64,     // Stake burned
+    pub jailed_until: u64,
+}
+
+/// Proof-of-stake: time is split into slots, and only the stake-weighted
+/// proposer of a slot may produce its block.
+pub struct ProofOfStake;
+
+impl ConsensusEngine for ProofOfStake {
+    /// Until the next slot boundary, so nodes agree on the slot.
+    fn wait(&self) -> Duration {
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        let slot_ms = BLOCK_TIME.as_millis() as u64;
+        Duration::from_millis(slot_ms - now_ms % slot_ms)
+    }
+
+    fn producer(&self, blockchain: &Blockchain, our_validators: &[String], timestamp: u64) -> Option<String> {
+        let slot = slot_at(timestamp);
+        let tip = blockchain.get_last_block();
+        if tip.as_ref().is_some_and(|tip| slot_at(tip.timestamp) >= slot) {
+            return None; // This slot already has a block
+        }
+
+        let previous_hash = tip.map_or_else(|| "0".to_string(), |tip| tip.hash);
+        let Some(proposer) = blockchain.select_proposer(&previous_hash, slot) else {
+            error!("No validators with stake, cannot produce blocks");
+            return None;
+        };
+        if !our_validators.contains(&proposer.address) {
+            debug!("Slot {} belongs to validator {}", slot, proposer.address);
+            return None;
+        }
+        Some(proposer.address)
+    }
+
+    fn seal(&self, _blockchain: &Blockchain, block: &mut Block) -> bool {
+        block.proof = 0;
+        block.difficulty = 0;
+        block.hash = block.header().calculate_hash();
+        true
+    }
+
+    /// The producer must be a validator this node knows of, or stake in an
+    /// earlier block of the branch. Proposers are picked from the validators
+    /// at the parent, which only the state shows.
+    fn verify_header(&self, blockchain: &Blockchain, block: &Block, parent: &Block, earlier: &[Block]) -> Result<(), BlockError> {
+        if block.difficulty != 0 || block.proof != 0 {
+            return Err(BlockError::InvalidSeal("Proof-of-stake block has a proof-of-work seal"));
+        }
+        if slot_at(block.timestamp) <= slot_at(parent.timestamp) {
+            return Err(BlockError::InvalidSeal("Slot already has a block"));
+        }
+        let stakes_earlier = earlier
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .any(|tx| tx.kind == TransactionKind::Stake && tx.sender == block.validator);
+        if blockchain.get_validator(&block.validator).is_none() && !stakes_earlier {
+            return Err(BlockError::InvalidSeal("Block producer is not a validator"));
+        }
+        Ok(())
+    }
+
+    fn verify(&self, blockchain: &Blockchain, block: &Block) -> Result<(), BlockError> {
+        let proposer = blockchain.select_proposer(&block.previous_hash, slot_at(block.timestamp));
+        match proposer {
+            Some(proposer) if proposer.address == block.validator => Ok(()),
+            proposer => Err(BlockError::NotProposer { expected: proposer.map(|proposer| proposer.address) }),
+        }
+    }
+
+    fn tracks_downtime(&self) -> bool {
+        true
+    }
+}
+
+/// Development validators in the bundled `genesis.json`. Validator `i` stakes `100 * (i + 1)` tokens.
+pub const DEV_VALIDATORS: u32 = 3;
+
+/// Deterministic private key of development validator `index`.
+pub fn dev_validator_key(index: u32) -> String {
+    hex::encode(Sha256::digest(format!("MOHSIN dev validator {}", index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator(address: &str, stake: u64, delegated: u64) -> Validator {
+        let mut validator = Validator { stake, delegated, ..Validator::new(address) };
+        validator.update_status();
+        validator
+    }
+
+    #[test]
+    fn selection_is_deterministic() {
+        let validators = [validator("a", 100, 0), validator("b", 200, 0), validator("c", 300, 0)];
+        for slot in 0..50 {
+            let first = select_validator(&validators, "parent", slot).unwrap();
+            let second = select_validator(&validators, "parent", slot).unwrap();
+            assert_eq!(first.address, second.address);
+        }
+    }
+
+    #[test]
+    fn only_active_validators_are_selected() {
+        let mut jailed = validator("c", 10_000, 0);
+        jailed.status = ValidatorStatus::Jailed;
+        let validators = [validator("a", MIN_VALIDATOR_STAKE - 1, 10_000), validator("b", 100, 0), jailed];
+        for slot in 0..100 {
+            assert_eq!(select_validator(&validators, "parent", slot).unwrap().address, "b");
+        }
+        assert!(select_validator(&validators[..1], "parent", 0).is_none());
+        assert!(select_validator(&[], "parent", 0).is_none());
+    }
+
+    #[test]
+    fn selection_is_weighted_by_own_and_delegated_stake() {
+        let validators = [validator("a", 100, 0), validator("b", 100, 200)];
+        let slots = 4_000;
+        let picked_b = (0..slots)
+            .filter(|slot| select_validator(&validators, "parent", *slot).unwrap().address == "b")
+            .count();
+        // b holds three quarters of the stake
+        assert!((2_800..3_200).contains(&picked_b), "b picked {} times out of {}", picked_b, slots);
+    }
+
+    #[test]
+    fn percent_of_does_not_overflow() {
+        assert_eq!(percent_of(u64::MAX, 10), u64::MAX / 10);
+        assert_eq!(percent_of(u64::MAX, 100), u64::MAX);
+        assert_eq!(percent_of(99, 1), 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use crate::blockchain::{Block, Blockchain, BLOCK_TIME};
+use crate::consensus::ConsensusEngine;
+use crate::error::BlockError;
+
+/// Leading zero bits required of the first blocks' hashes.
+pub const INITIAL_DIFFICULTY: u32 = 16;
+/// Blocks between difficulty adjustments.
+pub const RETARGET_INTERVAL: u64 = 10;
+/// Proofs tried between checks that the block being sealed is still on top of the tip.
+const ATTEMPTS_PER_CHECK: u64 = 4096;
+
+/// Proof-of-work: any node may produce the next block by finding a `proof`
+/// that makes the hash of the full header start with `difficulty` zero bits.
+/// Every `RETARGET_INTERVAL` blocks the difficulty moves one bit towards a
+/// block every `BLOCK_TIME`.
+pub struct ProofOfWork;
+
+impl ProofOfWork {
+    /// Difficulty the block at `index` must have, derived from its ancestors.
+    pub fn difficulty_at(blockchain: &Blockchain, index: u64) -> u32 {
+        let parent = match index.checked_sub(1).and_then(|parent| blockchain.get_block(parent)) {
+            Some(parent) if parent.difficulty > 0 => parent,
+            _ => return INITIAL_DIFFICULTY,
+        };
+        if !Self::is_retarget(index) {
+            return parent.difficulty;
+        }
+
+        let first = blockchain.get_block(index - RETARGET_INTERVAL).expect("Missing block in retarget window");
+        let actual = parent.timestamp.saturating_sub(first.timestamp);
+        let expected = BLOCK_TIME.as_secs() * (RETARGET_INTERVAL - 1);
+        if actual * 2 < expected {
+            parent.difficulty + 1
+        } else if actual > expected * 2 && parent.difficulty > 1 {
+            parent.difficulty - 1
+        } else {
+            parent.difficulty
+        }
+    }
+
+    /// Whether the difficulty may change at block `index`.
+    fn is_retarget(index: u64) -> bool {
+        index > RETARGET_INTERVAL && (index - 1).is_multiple_of(RETARGET_INTERVAL)
+    }
+
+    fn leading_zero_bits(hash: &str) -> u32 {
+        let mut bits = 0;
+        for digit in hash.chars().map(|c| c.to_digit(16).unwrap_or(0)) {
+            if digit != 0 {
+                return bits + (digit as u8).leading_zeros() - 4;
+            }
+            bits += 4;
+        }
+        bits
+    }
+}
+
+impl ConsensusEngine for ProofOfWork {
+    fn wait(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn producer(&self, _blockchain: &Blockchain, our_validators: &[String], _timestamp: u64) -> Option<String> {
+        our_validators.iter().min().cloned() // Always the same miner, so rewards are not spread over our keys
+    }
+
+    fn seal(&self, blockchain: &Blockchain, block: &mut Block) -> bool {
+        block.difficulty = Self::difficulty_at(blockchain, block.index);
+        let mut header = block.header();
+        for proof in 0u64.. {
+            if proof.is_multiple_of(ATTEMPTS_PER_CHECK) && blockchain.next_block_index() != block.index {
+                return false; // Someone else extended the chain first
+            }
+            header.proof = proof;
+            let hash = header.calculate_hash();
+            if Self::leading_zero_bits(&hash) >= block.difficulty {
+                block.proof = proof;
+                block.hash = hash;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The hash must meet the difficulty, and the difficulty may only move
+    /// one bit from the parent's at a retarget, so a block's work is real.
+    fn verify_header(&self, _blockchain: &Blockchain, block: &Block, parent: &Block, _earlier: &[Block]) -> Result<(), BlockError> {
+        let parent_difficulty = if parent.difficulty > 0 { parent.difficulty } else { INITIAL_DIFFICULTY };
+        let max_change = if parent.difficulty > 0 && Self::is_retarget(block.index) { 1 } else { 0 };
+        if block.difficulty == 0 || block.difficulty.abs_diff(parent_difficulty) > max_change {
+            return Err(BlockError::InvalidSeal("Wrong proof-of-work difficulty"));
+        }
+        if Self::leading_zero_bits(&block.hash) < block.difficulty {
+            return Err(BlockError::InvalidSeal("Hash does not meet the proof-of-work difficulty"));
+        }
+        Ok(())
+    }
+
+    fn verify(&self, blockchain: &Blockchain, block: &Block) -> Result<(), BlockError> {
+        if block.difficulty != Self::difficulty_at(blockchain, block.index) {
+            return Err(BlockError::InvalidSeal("Wrong proof-of-work difficulty"));
+        }
+        Ok(())
+    }
+
+    /// Expected number of hashes behind the seal, so that the chain with the
+    /// most work wins even if retargeting made it shorter.
+    fn block_work(&self, block: &Block) -> u128 {
+        1u128.checked_shl(block.difficulty).unwrap_or(u128::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+
+    #[test]
+    fn leading_zero_bits_counts_bits_not_digits() {
+        assert_eq!(ProofOfWork::leading_zero_bits("f0"), 0);
+        assert_eq!(ProofOfWork::leading_zero_bits("8"), 0);
+        assert_eq!(ProofOfWork::leading_zero_bits("7f"), 1);
+        assert_eq!(ProofOfWork::leading_zero_bits("1f"), 3);
+        assert_eq!(ProofOfWork::leading_zero_bits("0f"), 4);
+        assert_eq!(ProofOfWork::leading_zero_bits("0000ab"), 16);
+        assert_eq!(ProofOfWork::leading_zero_bits("00003b"), 18);
+    }
+
+    #[test]
+    fn all_zero_hash_counts_every_digit() {
+        assert_eq!(ProofOfWork::leading_zero_bits(""), 0);
+        assert_eq!(ProofOfWork::leading_zero_bits(&"0".repeat(64)), 256);
+    }
+
+    fn block(index: u64, difficulty: u32, zero_digits: usize) -> Block {
+        let mut block = Genesis::default().block();
+        block.index = index;
+        block.difficulty = difficulty;
+        block.hash = format!("{}{}", "0".repeat(zero_digits), "f".repeat(64 - zero_digits));
+        block
+    }
+
+    #[test]
+    fn header_work_must_be_real() {
+        let chain = Blockchain::temporary(&Genesis::default());
+        let genesis = chain.get_block(0).unwrap();
+        let verify = |block: &Block, parent: &Block| ProofOfWork.verify_header(&chain, block, parent, &[]);
+        let wrong_difficulty = Err(BlockError::InvalidSeal("Wrong proof-of-work difficulty"));
+
+        assert_eq!(verify(&block(1, INITIAL_DIFFICULTY, 4), &genesis), Ok(()));
+        assert_eq!(verify(&block(1, 64, 16), &genesis), wrong_difficulty);
+        assert_eq!(verify(&block(1, INITIAL_DIFFICULTY, 3), &genesis), Err(BlockError::InvalidSeal("Hash does not meet the proof-of-work difficulty")));
+
+        let parent = block(10, 16, 4);
+        assert_eq!(verify(&block(11, 17, 5), &parent), Ok(()));
+        assert_eq!(verify(&block(11, 18, 5), &parent), wrong_difficulty);
+        assert_eq!(verify(&block(12, 17, 5), &block(11, 16, 4)), wrong_difficulty); // Not a retarget
+    }
+}
@@ -0,0 +1,242 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::api::{BalanceResponse, BlockDetails, SubmitResponse, TransactionDetails};
+use crate::blockchain::{Blockchain, Transaction};
+use crate::error::ChainError;
+use crate::network::Network;
+
+// Error codes defined by the JSON-RPC 2.0 specification
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// A `ChainError`; its stable code is in `data.code`.
+pub const CHAIN_ERROR: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into(), data: None }
+    }
+}
+
+impl From<ChainError> for RpcError {
+    fn from(e: ChainError) -> Self {
+        RpcError { code: CHAIN_ERROR, message: e.to_string(), data: Some(json!({ "code": e.code() })) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+impl RpcResponse {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse { jsonrpc: "2.0".to_string(), result, error, id }
+    }
+}
+
+/// JSON-RPC 2.0 over the same `Blockchain` operations as the REST routes.
+///
+/// | Method                 | Params                | Result               |
+/// |------------------------|-----------------------|----------------------|
+/// | `chain_getHead`        |                       | `BlockDetails`       |
+/// | `chain_getBlock`       | `height`              | `BlockDetails`       |
+/// | `chain_getBlockByHash` | `hash`                | `BlockDetails`       |
+/// | `state_getBalance`     | `address`             | `BalanceResponse`    |
+/// | `state_getNonce`       | `address`             | next nonce           |
+/// | `tx_submit`            | `transaction`         | `SubmitResponse`     |
+/// | `tx_get`               | `id`                  | `TransactionDetails` |
+///
+/// Params are given by position or by name.
+pub struct Rpc<'a> {
+    pub blockchain: &'a Blockchain,
+    pub network: &'a Network,
+}
+
+impl Rpc<'_> {
+    /// Handles a request body holding a single call or a batch. Returns
+    /// `None` when nothing must be sent back because every call was a notification.
+    pub fn handle(&self, body: &[u8]) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return Some(json!(RpcResponse::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))))),
+        };
+        match request {
+            Value::Array(calls) if calls.is_empty() => {
+                Some(json!(RpcResponse::new(Value::Null, Err(RpcError::new(INVALID_REQUEST, "Empty batch")))))
+            }
+            Value::Array(calls) => {
+                let responses: Vec<RpcResponse> = calls.into_iter().filter_map(|call| self.call(call)).collect();
+                (!responses.is_empty()).then(|| json!(responses))
+            }
+            call => self.call(call).map(|response| json!(response)),
+        }
+    }
+
+    fn call(&self, call: Value) -> Option<RpcResponse> {
+        let Value::Object(mut call) = call else {
+            return Some(RpcResponse::new(Value::Null, Err(RpcError::new(INVALID_REQUEST, "Call must be an object"))));
+        };
+        let id = call.remove("id");
+        if id.as_ref().is_some_and(|id| !matches!(id, Value::Null | Value::Number(_) | Value::String(_))) {
+            return Some(RpcResponse::new(Value::Null, Err(RpcError::new(INVALID_REQUEST, "id must be a string, number or null"))));
+        }
+        let (method, params) = match (call.remove("jsonrpc"), call.remove("method"), call.remove("params")) {
+            (Some(Value::String(version)), Some(Value::String(method)), params) if version == "2.0" => match params {
+                None => (method, Value::Array(Vec::new())),
+                Some(params) if params.is_array() || params.is_object() => (method, params),
+                Some(_) => return Some(RpcResponse::new(id.unwrap_or_default(), Err(RpcError::new(INVALID_REQUEST, "params must be an array or an object")))),
+            },
+            _ => return Some(RpcResponse::new(id.unwrap_or_default(), Err(RpcError::new(INVALID_REQUEST, "Expected jsonrpc \"2.0\" and a method")))),
+        };
+        let outcome = self.dispatch(&method, &params);
+        id.map(|id| RpcResponse::new(id, outcome)) // Notifications get no response
+    }
+
+    fn dispatch(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        let blockchain = self.blockchain;
+        let result = match method {
+            "chain_getHead" => {
+                let block = blockchain
+                    .get_last_block()
+                    .ok_or(ChainError::BlockNotFound(blockchain.next_block_index() - 1))?;
+                json!(BlockDetails::new(block, blockchain))
+            }
+            "chain_getBlock" => {
+                let height: u64 = param(params, 0, "height")?;
+                let block = blockchain.get_block(height).ok_or(ChainError::BlockNotFound(height))?;
+                json!(BlockDetails::new(block, blockchain))
+            }
+            "chain_getBlockByHash" => {
+                let hash: String = param(params, 0, "hash")?;
+                let block = blockchain.get_block_by_hash(&hash).ok_or(ChainError::BlockHashNotFound(hash))?;
+                json!(BlockDetails::new(block, blockchain))
+            }
+            "state_getBalance" => json!(BalanceResponse::new(param(params, 0, "address")?, blockchain)),
+            "state_getNonce" => {
+                let address: String = param(params, 0, "address")?;
+                json!(blockchain.get_next_nonce(&address))
+            }
+            "tx_submit" => {
+                let mut transaction: Transaction = param(params, 0, "transaction")?;
+                transaction.fill_id();
+                blockchain.add_transaction(transaction.clone())?;
+                self.network.broadcast_transaction(&transaction);
+                json!(SubmitResponse::pending(&transaction))
+            }
+            "tx_get" => {
+                let id: String = param(params, 0, "id")?;
+                json!(TransactionDetails::lookup(&id, blockchain).ok_or(ChainError::TransactionNotFound(id))?)
+            }
+            _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        };
+        Ok(result)
+    }
+}
+
+/// The param at `position` in an array of params, or called `name` in an object of them.
+fn param<T: DeserializeOwned>(params: &Value, position: usize, name: &str) -> Result<T, RpcError> {
+    let value = match params {
+        Value::Array(params) => params.get(position),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    };
+    let value = value.ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing param {}", name)))?;
+    serde_json::from_value(value.clone()).map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid param {}: {}", name, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testing::*;
+
+    fn handle(blockchain: &Blockchain, body: Value) -> Option<Value> {
+        let network = Network::new(Vec::new(), blockchain.genesis_hash());
+        Rpc { blockchain, network: &network }.handle(body.to_string().as_bytes())
+    }
+
+    fn call(method: &str, params: Value, id: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id })
+    }
+
+    #[test]
+    fn params_are_given_by_position_or_by_name() {
+        let chain = chain();
+        let alice = address(&key("alice"));
+        let by_position = handle(&chain, call("state_getBalance", json!([alice]), json!(1))).unwrap();
+        let by_name = handle(&chain, call("state_getBalance", json!({ "address": alice }), json!(1))).unwrap();
+        assert_eq!(by_position, by_name);
+        assert_eq!(by_position["result"]["balance"], 1000);
+
+        let missing = handle(&chain, call("chain_getBlock", json!([]), json!("a"))).unwrap();
+        assert_eq!((missing["id"].clone(), missing["error"]["code"].clone()), (json!("a"), json!(INVALID_PARAMS)));
+        let not_found = handle(&chain, call("chain_getBlock", json!([7]), json!(2))).unwrap();
+        assert_eq!(not_found["error"]["code"], CHAIN_ERROR);
+        assert_eq!(not_found["error"]["data"]["code"], "block_not_found");
+    }
+
+    #[test]
+    fn batch_answers_every_call_but_notifications_in_order() {
+        let chain = chain();
+        let batch = json!([
+            call("chain_getHead", json!([]), json!(1)),
+            { "jsonrpc": "2.0", "method": "chain_getHead" },
+            call("no_such_method", json!([]), json!(2)),
+            7,
+            call("chain_getBlock", json!([0]), json!("three")),
+        ]);
+        let responses = handle(&chain, batch).unwrap();
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["index"], 0);
+        assert_eq!((responses[1]["id"].clone(), responses[1]["error"]["code"].clone()), (json!(2), json!(METHOD_NOT_FOUND)));
+        assert_eq!((responses[2]["id"].clone(), responses[2]["error"]["code"].clone()), (Value::Null, json!(INVALID_REQUEST)));
+        assert_eq!(responses[3]["id"], "three");
+    }
+
+    #[test]
+    fn notifications_run_without_a_response() {
+        let chain = chain();
+        let tx = transfer(&key("alice"), &address(&key("bob")), 1, 0);
+        let notification = json!({ "jsonrpc": "2.0", "method": "tx_submit", "params": [tx] });
+        assert_eq!(handle(&chain, notification.clone()), None);
+        assert_eq!(handle(&chain, json!([notification, { "jsonrpc": "2.0", "method": "chain_getHead" }])), None);
+        let status = handle(&chain, call("tx_get", json!([tx.id]), json!(1))).unwrap();
+        assert_eq!(status["result"]["status"], "pending");
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let chain = chain();
+        let network = Network::new(Vec::new(), chain.genesis_hash());
+        let rpc = Rpc { blockchain: &chain, network: &network };
+        let parse_error = rpc.handle(b"{").unwrap();
+        assert_eq!((parse_error["id"].clone(), parse_error["error"]["code"].clone()), (Value::Null, json!(PARSE_ERROR)));
+        assert_eq!(handle(&chain, json!([])).unwrap()["error"]["code"], INVALID_REQUEST);
+        let wrong_version = json!({ "jsonrpc": "1.0", "method": "chain_getHead", "id": 4 });
+        assert_eq!(handle(&chain, wrong_version).unwrap()["error"]["code"], INVALID_REQUEST);
+        assert_eq!(handle(&chain, call("chain_getHead", json!(5), json!(5))).unwrap()["error"]["code"], INVALID_REQUEST);
+        assert_eq!(handle(&chain, call("chain_getHead", json!([]), json!([6]))).unwrap()["error"]["code"], INVALID_REQUEST);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use sled::{Db, Tree};
+
+use crate::monetary::Supply;
+use crate::pos::{self, SlashEvent, SlashReason, Validator};
+
+/// Key of the single entry in the supply tree.
+const SUPPLY_KEY: &str = "supply";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64, // Number of transactions applied from this account
+}
+
+/// The sled trees that make up the account state. Blocks only change state
+/// through a `StateOverlay`, so every change can be undone on a reorganization.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateTree {
+    Accounts,    // Address -> Account
+    Validators,  // Address -> Validator
+    Delegations, // "delegator:validator" -> delegated amount
+    Unbonding,   // "release height (20 digits):address:validator" -> amount unbonding from validator, released to address
+    Slashes,     // "offence height (20 digits):validator:reason" -> SlashEvent
+    Supply,      // SUPPLY_KEY -> Supply
+    UnbondingIndex, // "address:validator:release height (20 digits)" -> release height, one per Unbonding entry
+}
+
+impl StateTree {
+    pub const ALL: [StateTree; 7] = [
+        StateTree::Accounts,
+        StateTree::Validators,
+        StateTree::Delegations,
+        StateTree::Unbonding,
+        StateTree::Slashes,
+        StateTree::Supply,
+        StateTree::UnbondingIndex,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            StateTree::Accounts => "accounts",
+            StateTree::Validators => "validators",
+            StateTree::Delegations => "delegations",
+            StateTree::Unbonding => "unbonding",
+            StateTree::Slashes => "slashes",
+            StateTree::Supply => "supply",
+            StateTree::UnbondingIndex => "unbonding_index",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StateTrees {
+    trees: Vec<Tree>, // Indexed like `StateTree::ALL`
+}
+
+impl StateTrees {
+    pub fn open(db: &Db) -> Self {
+        let trees = StateTree::ALL
+            .iter()
+            .map(|tree| db.open_tree(tree.name()).unwrap_or_else(|_| panic!("Failed to open {} tree", tree.name())))
+            .collect();
+        let state = StateTrees { trees };
+        if state.tree(StateTree::UnbondingIndex).is_empty() {
+            state.index_unbonding(); // Database from before the index existed
+        }
+        state
+    }
+
+    fn index_unbonding(&self) {
+        for key in self.tree(StateTree::Unbonding).iter().keys() {
+            let key = String::from_utf8(key.expect("Failed to read unbonding from database").to_vec()).unwrap();
+            let (release, rest) = key.split_once(':').unwrap();
+            let (address, validator) = rest.split_once(':').unwrap();
+            let release: u64 = release.parse().unwrap();
+            self.tree(StateTree::UnbondingIndex)
+                .insert(unbonding_index_key(address, validator, release), serde_json::to_vec(&release).unwrap())
+                .expect("Failed to write unbonding index");
+        }
+    }
+
+    pub fn tree(&self, tree: StateTree) -> &Tree {
+        &self.trees[tree as usize]
+    }
+
+    pub fn all(&self) -> &[Tree] {
+        &self.trees
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.iter().all(|tree| tree.is_empty())
+    }
+
+    pub fn supply(&self) -> Supply {
+        self.get(StateTree::Supply, SUPPLY_KEY).unwrap_or_default()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, tree: StateTree, key: &str) -> Option<T> {
+        self.tree(tree)
+            .get(key)
+            .unwrap_or_else(|_| panic!("Failed to read {} from database", tree.name()))
+            .map(|value| serde_json::from_slice(&value).unwrap_or_else(|_| panic!("Corrupt entry in {} tree", tree.name())))
+    }
+}
+
+/// A raw state write: the new JSON value, or `None` to delete the key.
+pub type StateWrite = ((StateTree, String), Option<Vec<u8>>);
+
+/// Uncommitted changes on top of the stored state. Reads see earlier writes
+/// to the overlay first and fall through to sled; nothing is stored until the
+/// caller commits `writes()`.
+pub struct StateOverlay<'a> {
+    trees: &'a StateTrees,
+    writes: HashMap<(StateTree, String), Option<Vec<u8>>>,
+    journal: HashMap<(StateTree, String), Option<Option<Vec<u8>>>>, // Overlay entry of each key before the checkpoint, `None` if it had none
+}
+
+impl<'a> StateOverlay<'a> {
+    pub fn new(trees: &'a StateTrees) -> Self {
+        StateOverlay { trees, writes: HashMap::new(), journal: HashMap::new() }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, tree: StateTree, key: &str) -> Option<T> {
+        match self.writes.get(&(tree, key.to_string())) {
+            Some(value) => value.as_ref().map(|value| serde_json::from_slice(value).unwrap()),
+            None => self.trees.get(tree, key),
+        }
+    }
+
+    pub fn put<T: Serialize>(&mut self, tree: StateTree, key: &str, value: Option<&T>) {
+        let value = value.map(|value| serde_json::to_vec(value).unwrap());
+        let previous = self.writes.insert((tree, key.to_string()), value);
+        self.journal.entry((tree, key.to_string())).or_insert(previous);
+    }
+
+    /// Keeps every write so far; `revert` only undoes the ones made after this.
+    pub fn checkpoint(&mut self) {
+        self.journal.clear();
+    }
+
+    /// Undoes every write since the last `checkpoint`.
+    pub fn revert(&mut self) {
+        for (key, previous) in self.journal.drain() {
+            match previous {
+                Some(value) => self.writes.insert(key, value),
+                None => self.writes.remove(&key),
+            };
+        }
+    }
+
+    pub fn writes(self) -> Vec<StateWrite> {
+        self.writes.into_iter().collect()
+    }
+
+    /// Stored value of every key this overlay writes, to undo the writes later.
+    pub fn previous_values(&self) -> Vec<StateWrite> {
+        self.writes
+            .keys()
+            .map(|(tree, key)| {
+                let previous = self.trees.tree(*tree)
+                    .get(key)
+                    .unwrap_or_else(|_| panic!("Failed to read {} from database", tree.name()))
+                    .map(|value| value.to_vec());
+                ((*tree, key.clone()), previous)
+            })
+            .collect()
+    }
+
+    pub fn account(&self, address: &str) -> Account {
+        self.get(StateTree::Accounts, address).unwrap_or_default()
+    }
+
+    pub fn set_account(&mut self, address: &str, account: &Account) {
+        self.put(StateTree::Accounts, address, Some(account));
+    }
+
+    pub fn validator(&self, address: &str) -> Option<Validator> {
+        self.get(StateTree::Validators, address)
+    }
+
+    pub fn set_validator(&mut self, address: &str, validator: Option<&Validator>) {
+        self.put(StateTree::Validators, address, validator);
+    }
+
+    pub fn delegation(&self, delegator: &str, validator: &str) -> u64 {
+        self.get(StateTree::Delegations, &delegation_key(delegator, validator)).unwrap_or(0)
+    }
+
+    pub fn set_delegation(&mut self, delegator: &str, validator: &str, amount: u64) {
+        let key = delegation_key(delegator, validator);
+        self.put(StateTree::Delegations, &key, Some(&amount).filter(|amount| **amount > 0));
+    }
+
+    pub fn add_unbonding(&mut self, release_height: u64, address: &str, validator: &str, amount: u64) {
+        let key = unbonding_key(release_height, address, validator);
+        let pending: u64 = self.get(StateTree::Unbonding, &key).unwrap_or(0);
+        self.put(StateTree::Unbonding, &key, Some(&pending.saturating_add(amount)));
+        self.put(StateTree::UnbondingIndex, &unbonding_index_key(address, validator, release_height), Some(&release_height));
+    }
+
+    pub fn slash_event(&self, height: u64, validator: &str, reason: SlashReason) -> Option<SlashEvent> {
+        self.get(StateTree::Slashes, &slash_key(height, validator, reason))
+    }
+
+    pub fn add_slash_event(&mut self, event: &SlashEvent) {
+        let key = slash_key(event.height, &event.validator, event.reason);
+        self.put(StateTree::Slashes, &key, Some(event));
+    }
+
+    pub fn supply(&self) -> Supply {
+        self.get(StateTree::Supply, SUPPLY_KEY).unwrap_or_default()
+    }
+
+    pub fn set_supply(&mut self, supply: &Supply) {
+        self.put(StateTree::Supply, SUPPLY_KEY, Some(supply));
+    }
+
+    /// Burns `percent` of every entry `address` has unbonding from
+    /// `validator`, including entries added earlier in this overlay. Returns
+    /// the amount burned.
+    pub fn slash_unbonding(&mut self, address: &str, validator: &str, percent: u64) -> u64 {
+        let prefix = format!("{}:{}:", address, validator);
+        let stored = self.trees.tree(StateTree::UnbondingIndex)
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| String::from_utf8(key.expect("Failed to read unbonding index from database").to_vec()).unwrap());
+        let added = self.writes.keys().filter(|(tree, key)| *tree == StateTree::UnbondingIndex && key.starts_with(&prefix)).map(|(_, key)| key.clone());
+        let mut releases: Vec<u64> = stored.chain(added).filter_map(|key| self.get(StateTree::UnbondingIndex, &key)).collect();
+        releases.sort();
+        releases.dedup();
+
+        let mut slashed: u64 = 0;
+        for release in releases {
+            let key = unbonding_key(release, address, validator);
+            if let Some(amount) = self.get::<u64>(StateTree::Unbonding, &key) {
+                let burned = pos::percent_of(amount, percent);
+                slashed = slashed.saturating_add(burned);
+                self.put(StateTree::Unbonding, &key, Some(&(amount - burned)));
+            }
+        }
+        slashed
+    }
+
+    /// Removes and returns every unbonding entry released at `height`, as
+    /// (address, validator, amount).
+    pub fn take_unbonding(&mut self, height: u64) -> Vec<(String, String, u64)> {
+        let prefix = format!("{:020}:", height);
+        let keys: Vec<String> = self.trees.tree(StateTree::Unbonding)
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| String::from_utf8(key.expect("Failed to read unbonding from database").to_vec()).unwrap())
+            .collect();
+
+        let mut released = Vec::new();
+        for key in keys {
+            if let Some(amount) = self.get::<u64>(StateTree::Unbonding, &key) {
+                let (address, validator) = key[prefix.len()..].split_once(':').unwrap();
+                self.put::<u64>(StateTree::UnbondingIndex, &unbonding_index_key(address, validator, height), None);
+                released.push((address.to_string(), validator.to_string(), amount));
+                self.put::<u64>(StateTree::Unbonding, &key, None);
+            }
+        }
+        released
+    }
+}
+
+fn delegation_key(delegator: &str, validator: &str) -> String {
+    format!("{}:{}", delegator, validator)
+}
+
+fn unbonding_key(release_height: u64, address: &str, validator: &str) -> String {
+    format!("{:020}:{}:{}", release_height, address, validator)
+}
+
+fn unbonding_index_key(address: &str, validator: &str, release_height: u64) -> String {
+    format!("{}:{}:{:020}", address, validator, release_height)
+}
+
+fn slash_key(height: u64, validator: &str, reason: SlashReason) -> String {
+    format!("{:020}:{}:{}", height, validator, reason.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trees() -> StateTrees {
+        StateTrees::open(&sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    fn balance(state: &StateOverlay, address: &str) -> u64 {
+        state.account(address).balance
+    }
+
+    fn credit(state: &mut StateOverlay, address: &str, balance: u64) {
+        state.set_account(address, &Account { balance, nonce: 0 });
+    }
+
+    #[test]
+    fn revert_undoes_only_the_writes_since_the_checkpoint() {
+        let trees = trees();
+        let stored = serde_json::to_vec(&Account { balance: 7, nonce: 0 }).unwrap();
+        trees.tree(StateTree::Accounts).insert("carol", stored).unwrap();
+
+        let mut state = StateOverlay::new(&trees);
+        credit(&mut state, "alice", 1);
+        state.checkpoint();
+        credit(&mut state, "alice", 2);
+        credit(&mut state, "alice", 3);
+        credit(&mut state, "bob", 4);
+        state.put::<Account>(StateTree::Accounts, "carol", None);
+        assert_eq!((balance(&state, "alice"), balance(&state, "bob"), balance(&state, "carol")), (3, 4, 0));
+
+        state.revert();
+        assert_eq!((balance(&state, "alice"), balance(&state, "bob"), balance(&state, "carol")), (1, 0, 7));
+        state.revert(); // Nothing written since
+        assert_eq!(balance(&state, "alice"), 1);
+        let keys: Vec<String> = state.writes().into_iter().map(|((_, key), _)| key).collect();
+        assert_eq!(keys, ["alice"]);
+    }
+
+    #[test]
+    fn checkpoint_keeps_earlier_writes() {
+        let trees = trees();
+        let mut state = StateOverlay::new(&trees);
+        credit(&mut state, "alice", 1);
+        state.checkpoint();
+        credit(&mut state, "alice", 2);
+        state.checkpoint();
+        credit(&mut state, "alice", 3);
+        state.revert();
+        assert_eq!(balance(&state, "alice"), 2);
+        assert_eq!(state.previous_values(), [((StateTree::Accounts, "alice".to_string()), None)]);
+    }
+}
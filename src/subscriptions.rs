@@ -0,0 +1,217 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use log::{debug, warn};
+use tokio::sync::broadcast::error::RecvError;
+use warp::ws::{Message, WebSocket};
+
+use crate::api::TransactionDetails;
+use crate::blockchain::{Block, Blockchain, IncludedTransaction, Transaction};
+
+/// Events that `Blockchain` publishes to subscribers.
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    NewHead(Block),                       // A block was added to the tip
+    RolledBack(Block),                    // The tip block was removed by a reorganization
+    PendingTransaction(Box<Transaction>), // A transaction entered the mempool, or returned to it after a reorganization
+}
+
+/// What a WebSocket client can subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Topic {
+    NewHeads,            // Header of every block added to the tip
+    PendingTransactions, // Every transaction entering the mempool
+    Address(String),     // Transactions sent or received by the address, when pending and when included
+}
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "newHeads" => Ok(Topic::NewHeads),
+            "pendingTransactions" => Ok(Topic::PendingTransactions),
+            _ => match value.strip_prefix("address:") {
+                Some(address) if !address.is_empty() => Ok(Topic::Address(address.to_string())),
+                _ => Err(format!("Unknown topic {}, expected newHeads, pendingTransactions or address:<address>", value)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Topic::NewHeads => write!(f, "newHeads"),
+            Topic::PendingTransactions => write!(f, "pendingTransactions"),
+            Topic::Address(address) => write!(f, "address:{}", address),
+        }
+    }
+}
+
+/// A message from the client: `{"subscribe": "<topic>"}` or `{"unsubscribe": "<topic>"}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+impl ChainEvent {
+    /// The messages this event produces for a client subscribed to `topics`,
+    /// as `{"topic": "<topic>", "data": ...}`. Messages about a rolled-back
+    /// block and its transactions also carry `"removed": true`.
+    fn messages(&self, topics: &HashSet<Topic>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        let transactions: Vec<TransactionDetails> = match self {
+            ChainEvent::NewHead(block) | ChainEvent::RolledBack(block) => {
+                if topics.contains(&Topic::NewHeads) {
+                    messages.push((Topic::NewHeads, json!(block.header())));
+                }
+                block.transactions
+                    .iter()
+                    .enumerate()
+                    .map(|(index_in_block, transaction)| {
+                        let included = IncludedTransaction {
+                            block_index: block.index,
+                            block_hash: block.hash.clone(),
+                            index_in_block,
+                            transaction: transaction.clone(),
+                        };
+                        TransactionDetails::included(included, false)
+                    })
+                    .collect()
+            }
+            ChainEvent::PendingTransaction(transaction) => {
+                if topics.contains(&Topic::PendingTransactions) {
+                    messages.push((Topic::PendingTransactions, json!(transaction.as_ref())));
+                }
+                vec![TransactionDetails::pending(transaction.as_ref().clone())]
+            }
+        };
+        for details in transactions {
+            let transaction = &details.transaction;
+            let mut addresses = vec![&transaction.sender];
+            if transaction.recipient != transaction.sender {
+                addresses.push(&transaction.recipient);
+            }
+            for address in addresses {
+                let topic = Topic::Address(address.clone());
+                if topics.contains(&topic) {
+                    messages.push((topic, json!(details)));
+                }
+            }
+        }
+        let removed = matches!(self, ChainEvent::RolledBack(_));
+        messages
+            .into_iter()
+            .map(|(topic, data)| {
+                let mut message = json!({ "topic": topic.to_string(), "data": data });
+                if removed {
+                    message["removed"] = json!(true);
+                }
+                message
+            })
+            .collect()
+    }
+}
+
+/// Serves one WebSocket client until it disconnects. Events are pushed as
+/// described in `ChainEvent::messages`.
+pub async fn serve(socket: WebSocket, blockchain: Arc<Blockchain>) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = blockchain.subscribe();
+    let mut topics = HashSet::new();
+
+    loop {
+        let outgoing = tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(message)) if message.is_text() => vec![handle_command(message.to_str().unwrap(), &mut topics)],
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(_)) => continue, // Pings are answered by warp
+                Some(Err(e)) => {
+                    debug!("WebSocket error: {}", e);
+                    break;
+                }
+                None => break,
+            },
+            event = events.recv() => match event {
+                Ok(event) => event.messages(&topics),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client fell behind, skipped {} events", skipped);
+                    vec![json!({ "error": { "code": "lagged", "message": format!("Skipped {} events", skipped) } })]
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        for message in outgoing {
+            if sender.send(Message::text(message.to_string())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn handle_command(text: &str, topics: &mut HashSet<Topic>) -> Value {
+    let command = match serde_json::from_str(text) {
+        Ok(command) => command,
+        Err(e) => return json!({ "error": { "code": "invalid_command", "message": e.to_string() } }),
+    };
+    let (name, topic) = match &command {
+        Command::Subscribe(topic) => ("subscribed", topic),
+        Command::Unsubscribe(topic) => ("unsubscribed", topic),
+    };
+    let topic = match topic.parse::<Topic>() {
+        Ok(topic) => topic,
+        Err(message) => return json!({ "error": { "code": "unknown_topic", "message": message } }),
+    };
+    let reply = json!({ name: topic.to_string() });
+    match command {
+        Command::Subscribe(_) => topics.insert(topic),
+        Command::Unsubscribe(_) => topics.remove(&topic),
+    };
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testing::*;
+
+    #[test]
+    fn reorganization_reports_removed_blocks_and_returned_transactions() {
+        let (ours, theirs) = (chain(), chain());
+        let (alice, bob) = (key("alice"), address(&key("bob")));
+        let slot = first_slot();
+        let to_bob = transfer(&alice, &bob, 1, 0);
+        let removed = produce(&ours, slot, vec![to_bob.clone()]);
+        produce(&theirs, slot, vec![]);
+        produce(&theirs, slot + 1, vec![]);
+
+        let mut events = ours.subscribe();
+        ours.reorganize(theirs.get_blocks(1, 10)).unwrap();
+        let topics = HashSet::from([Topic::NewHeads, Topic::Address(bob.clone())]);
+        let messages: Vec<Value> = std::iter::from_fn(|| events.try_recv().ok()).flat_map(|event| event.messages(&topics)).collect();
+
+        let summary: Vec<(String, bool, Value)> = messages
+            .iter()
+            .map(|message| (message["topic"].as_str().unwrap().to_string(), message["removed"] == true, message["data"]["index"].clone()))
+            .collect();
+        let address_topic = format!("address:{}", bob);
+        assert_eq!(summary, [
+            ("newHeads".to_string(), true, json!(1)),
+            (address_topic.clone(), true, Value::Null),
+            ("newHeads".to_string(), false, json!(1)),
+            ("newHeads".to_string(), false, json!(2)),
+            (address_topic, false, Value::Null),
+        ]);
+        assert_eq!(messages[0]["data"]["hash"], removed.hash);
+        assert_eq!(messages[1]["data"]["block_index"], 1);
+        assert_eq!(messages[4]["data"]["block_index"], Value::Null); // Pending again
+        assert_eq!((messages[4]["data"]["id"].clone(), messages[4]["data"]["status"].clone()), (json!(to_bob.id), json!("pending")));
+    }
+}